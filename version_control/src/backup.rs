@@ -0,0 +1,114 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::repository::Repository;
+use crate::utils;
+
+// Snapshots kept before the oldest ones are pruned
+pub const DEFAULT_RETENTION: usize = 10;
+
+// Working directory contents saved before a command overwrote them. File
+// contents live in the object store, so a snapshot is just a path -> hash map.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    pub id: String,
+    pub timestamp: DateTime<Utc>,
+    pub reason: String,
+    pub files: HashMap<String, String>,
+}
+
+fn backups_dir(repo: &Repository) -> PathBuf {
+    repo.repo_dir().join("backups")
+}
+
+// Snapshot `paths` (relative to the working dir) and prune down to `retention` backups
+pub fn create(repo: &Repository, paths: &[String], reason: &str, retention: usize) -> std::io::Result<Backup> {
+    let mut files = HashMap::new();
+    for path in paths {
+        let content = fs::read(repo.working_dir.join(path))?;
+        let hash = utils::calculate_hash_bytes(&content);
+        repo.write_object(&hash, &content)?;
+        files.insert(path.clone(), hash);
+    }
+
+    let dir = backups_dir(repo);
+    fs::create_dir_all(&dir)?;
+
+    // IDs sort chronologically; bump the suffix if two land in the same millisecond
+    let timestamp = Utc::now();
+    let base_id = timestamp.format("%Y%m%d-%H%M%S-%3f").to_string();
+    let mut id = base_id.clone();
+    let mut counter = 1;
+    while dir.join(format!("{}.json", id)).exists() {
+        counter += 1;
+        id = format!("{}.{}", base_id, counter);
+    }
+
+    let backup = Backup {
+        id,
+        timestamp,
+        reason: reason.to_string(),
+        files,
+    };
+    let json = serde_json::to_string_pretty(&backup)?;
+    utils::write_file_atomic(&dir.join(format!("{}.json", backup.id)), json.as_bytes())?;
+
+    prune(repo, retention)?;
+    Ok(backup)
+}
+
+// All backups, oldest first
+pub fn list(repo: &Repository) -> std::io::Result<Vec<Backup>> {
+    let dir = backups_dir(repo);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            let content = fs::read_to_string(&path)?;
+            let backup: Backup = serde_json::from_str(&content).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Corrupt backup {}: {}", path.display(), e),
+                )
+            })?;
+            backups.push(backup);
+        }
+    }
+    backups.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(backups)
+}
+
+// Look up a backup by ID or unambiguous ID prefix
+pub fn find(repo: &Repository, id: &str) -> std::io::Result<Backup> {
+    let mut matches: Vec<Backup> = list(repo)?.into_iter().filter(|b| b.id.starts_with(id)).collect();
+    if let Some(index) = matches.iter().position(|b| b.id == id) {
+        return Ok(matches.swap_remove(index));
+    }
+    match matches.len() {
+        1 => Ok(matches.remove(0)),
+        0 => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Backup not found: {}", id),
+        )),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Backup ID is ambiguous: {}", id),
+        )),
+    }
+}
+
+fn prune(repo: &Repository, retention: usize) -> std::io::Result<()> {
+    let backups = list(repo)?;
+    let excess = backups.len().saturating_sub(retention);
+    for backup in &backups[..excess] {
+        fs::remove_file(backups_dir(repo).join(format!("{}.json", backup.id)))?;
+    }
+    Ok(())
+}
@@ -1,18 +1,36 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::env;
 use std::fs;
-use std::path::{Path};
+use std::io::{IsTerminal, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::Local;
 use walkdir::WalkDir;
 
-use crate::repository::Repository;
+use crate::backup;
+use crate::config::{self, Config, ConfigFile, Scope};
+use crate::diff::{self, DiffOp};
+use crate::gc;
+use crate::ignore::{IgnoreRules, IGNORE_FILE};
+use crate::index::{Index, Stat};
+use crate::merge::{self, ConflictKind, MergedFile};
+use crate::patch::{self, HunkResult};
+use crate::repository::{Head, Repository, DEFAULT_BRANCH};
 use crate::utils;
 
+// Runs shorter than this finish without a progress indicator
+const PROGRESS_DELAY: Duration = Duration::from_secs(1);
+
 // Initialize a new repository in the current directory
-pub fn init() -> std::io::Result<()> {
+// A bare repository has no working directory and is meant to be pushed to
+pub fn init(bare: bool) -> std::io::Result<()> {
     let working_dir = env::current_dir()?;
-    let repo_dir = working_dir.join(".mini-git");
+    let repo_dir = if bare { working_dir.clone() } else { working_dir.join(".mini-git") };
 
     // Check if repository already exists
-    if repo_dir.exists() {
+    if repo_dir.join("repository.json").exists() || (!bare && repo_dir.exists()) {
         return Err(std::io::Error::new(
             std::io::ErrorKind::AlreadyExists,
             "Repository already initialized",
@@ -21,266 +39,1519 @@ pub fn init() -> std::io::Result<()> {
 
     // Create repository directory and initialize repository
     fs::create_dir_all(&repo_dir)?;
-    let repo = Repository::new(working_dir);
+    let repo = if bare { Repository::new_bare(working_dir) } else { Repository::new(working_dir) };
     repo.save()?;
+    repo.set_head(&Head::Branch(DEFAULT_BRANCH.to_string()))?;
     println!("Initialized empty repository");
     Ok(())
 }
 
-// Add files to the staging area
-pub fn add(paths: &[String]) -> std::io::Result<()> {
-    let working_dir = env::current_dir()?;
-    let mut repo = Repository::load(working_dir.clone())?;
+// Add files to the staging area. Untracked files matched by .mini-gitignore,
+// whether named directly or found inside a directory, are skipped unless
+// `force` is set.
+pub fn add(paths: &[String], force: bool) -> std::io::Result<()> {
+    let mut repo = Repository::discover()?;
+    let working_dir = repo.working_dir.clone();
+    let rules = IgnoreRules::load(&working_dir)?;
+    let mut index = Index::load(&repo)?;
+    // What the next commit would record; those paths are added even if ignored
+    let staged_tree = repo.staged_tree();
+    let mut tree_files: Option<Vec<String>> = None;
+    let mut to_stage: BTreeSet<String> = BTreeSet::new();
     let mut files_added = false;
 
     for path_str in paths {
         let path = Path::new(path_str);
+        let relative_path = relative_path(&repo, path_str)?;
         
-        if path_str == "." {
-            // Handle adding all files in current directory
-            for entry in WalkDir::new(&working_dir)
-                .into_iter()
-                .filter_map(Result::ok)
-                .filter(|e| e.file_type().is_file())
-            {
-                let entry_path = entry.path();
-                // Skip .mini-git directory and hidden files
-                if !entry_path.starts_with(working_dir.join(".mini-git")) && 
-                   !entry_path.to_string_lossy().contains("/.") {
-                    match repo.stage_file(entry_path) {
-                        Ok(_) => {
-                            println!("Added: {}", entry_path.display());
-                            files_added = true;
-                        }
-                        Err(e) => eprintln!("Error adding {}: {}", entry_path.display(), e),
-                    }
-                }
+        if path.is_dir() {
+            // Handle directories, including ".", recursively
+            if tree_files.is_none() {
+                tree_files = Some(walk_working_dir(&repo, force)?);
             }
+            let files = tree_files.iter().flatten();
+            to_stage.extend(files.filter(|f| is_under(f, &relative_path)).cloned());
+            files_added |= stage_missing_files(&mut repo, &relative_path)?;
         } else if path.is_file() {
             // Handle single file
-            match repo.stage_file(path) {
-                Ok(_) => {
-                    println!("Added: {}", path.display());
-                    files_added = true;
-                }
-                Err(e) => eprintln!("Error adding {}: {}", path.display(), e),
-            }
-        } else if path.is_dir() {
-            // Handle directory recursively
-            for entry in WalkDir::new(path)
-                .into_iter()
-                .filter_map(Result::ok)
-                .filter(|e| e.file_type().is_file())
-            {
-                let entry_path = entry.path();
-                if !entry_path.to_string_lossy().contains("/.") {
-                    match repo.stage_file(entry_path) {
-                        Ok(_) => {
-                            println!("Added: {}", entry_path.display());
-                            files_added = true;
-                        }
-                        Err(e) => eprintln!("Error adding {}: {}", entry_path.display(), e),
-                    }
-                }
+            if !force && !staged_tree.contains_key(&relative_path) && rules.is_ignored(&relative_path, false) {
+                eprintln!("Skipping ignored path: {} (use --force to add it anyway)", path_str);
+                continue;
             }
+            to_stage.insert(relative_path);
+        } else if stage_missing_files(&mut repo, &relative_path)? {
+            // Tracked path that was deleted from the working directory
+            files_added = true;
         } else {
             eprintln!("Warning: Path not found or inaccessible: {}", path_str);
         }
     }
 
+    let to_stage: Vec<String> = to_stage.into_iter().collect();
+    files_added |= stage_files(&mut repo, &mut index, &to_stage);
+
     if files_added {
         repo.save()?;
         println!("Changes staged successfully");
     } else {
         println!("No files were added");
     }
+    index.save(&repo)?;
     
     Ok(())
 }
 
-pub fn commit(message: &str) -> std::io::Result<()> {
-    let working_dir = env::current_dir()?;
-    let mut repo = Repository::load(working_dir)?;
-    repo.commit(message)?;
+// What hashing one file for `add` found
+struct HashedFile {
+    hash: String,
+    // Stat data to cache, when the index couldn't vouch for the file
+    stat: Option<Stat>,
+    // Whether the content differs from what is staged, in which case it has
+    // been copied into the object store
+    changed: bool,
+}
+
+// Stage every file in `paths` whose content differs from what the next commit
+// would record. Files are hashed and stored on all cores; staging and output
+// happen afterwards in path order. Returns whether anything was staged.
+fn stage_files(repo: &mut Repository, index: &mut Index, paths: &[String]) -> bool {
+    let staged_tree = repo.staged_tree();
+    let results = {
+        let repo = &*repo;
+        let index = &*index;
+        parallel_map(paths, "Hashing files", |path| -> std::io::Result<HashedFile> {
+            let (stat, cached) = index.lookup(path)?;
+            let file_path = repo.working_dir.join(path);
+            let (hash, stat) = match cached {
+                Some(hash) => (hash, None),
+                None => (utils::hash_file(&file_path)?, Some(stat)),
+            };
+            let unchanged = staged_tree.get(path) == Some(&hash) && !repo.conflicts.contains_key(path);
+            if unchanged && repo.has_object(&hash) {
+                return Ok(HashedFile { hash, stat, changed: false });
+            }
+            // Hash again while copying, in case the file changed in between
+            let hash = repo.store_file(&file_path)?;
+            Ok(HashedFile { hash, stat, changed: true })
+        })
+    };
+
+    let mut files_added = false;
+    for (path, result) in paths.iter().zip(results) {
+        match result {
+            Ok(hashed) => {
+                if let Some(stat) = hashed.stat {
+                    index.record(path, stat, &hashed.hash);
+                }
+                if hashed.changed {
+                    repo.stage_entry(path, &hashed.hash);
+                    println!("Added: {}", path);
+                    files_added = true;
+                }
+            }
+            Err(e) => eprintln!("Error adding {}: {}", path, e),
+        }
+    }
+    files_added
+}
+
+// Run `f` over `items` on a thread per core, returning the results in order.
+// A progress count is shown on stderr once a run takes more than a moment.
+fn parallel_map<T, R, F>(items: &[T], label: &str, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let threads = thread::available_parallelism().map_or(1, |n| n.get()).min(items.len()).max(1);
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let started = Instant::now();
+
+    let mut results: Vec<(usize, R)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else { break };
+                        results.push((i, f(item)));
+                        done.fetch_add(1, Ordering::Relaxed);
+                    }
+                    results
+                })
+            })
+            .collect();
+
+        let show_progress = std::io::stderr().is_terminal();
+        let mut shown = false;
+        while !workers.iter().all(|worker| worker.is_finished()) {
+            thread::sleep(Duration::from_millis(50));
+            if show_progress && started.elapsed() >= PROGRESS_DELAY {
+                eprint!("\r{}: {}/{}", label, done.load(Ordering::Relaxed), items.len());
+                shown = true;
+            }
+        }
+        if shown {
+            eprintln!("\r{}: {}/{}, done.", label, items.len(), items.len());
+        }
+
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    });
+
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+// Turn a path given relative to the current directory into the form used as
+// a key in commits; the repository root itself becomes the empty string
+fn relative_path(repo: &Repository, path_str: &str) -> std::io::Result<String> {
+    // Resolve "." and ".." by hand, the path doesn't have to exist
+    let mut absolute = PathBuf::new();
+    for component in env::current_dir()?.join(path_str).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            other => absolute.push(other),
+        }
+    }
+
+    let relative = absolute.strip_prefix(&repo.working_dir).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Path is outside the repository: {}", path_str),
+        )
+    })?;
+    let components: Vec<String> = relative.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+    Ok(components.join("/"))
+}
+
+fn is_under(path: &str, prefix: &str) -> bool {
+    prefix.is_empty() || path == prefix || path.starts_with(&format!("{}/", prefix))
+}
+
+// Stage deletions for files under `prefix` that the next commit would record
+// but that no longer exist on disk; files not in HEAD are simply unstaged.
+// Deleting a conflicted file resolves the conflict that way.
+fn stage_missing_files(repo: &mut Repository, prefix: &str) -> std::io::Result<bool> {
+    let missing: Vec<String> = repo
+        .staged_tree()
+        .into_keys()
+        .chain(repo.conflicts.keys().cloned())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .filter(|path| is_under(path, prefix))
+        .filter(|path| !repo.working_dir.join(path).exists())
+        .collect();
+
+    for path in &missing {
+        repo.stage_removal(path)?;
+        println!("Removed: {}", path);
+    }
+    Ok(!missing.is_empty())
+}
+
+// Stop tracking paths, deleting them from the working directory unless `cached`
+pub fn rm(paths: &[String], cached: bool, recursive: bool, force: bool) -> std::io::Result<()> {
+    let mut repo = Repository::discover()?;
+    let working_dir = repo.working_dir.clone();
+    let tracked: BTreeSet<String> = repo
+        .staged_tree()
+        .into_keys()
+        .chain(repo.head_files().into_keys())
+        .chain(repo.conflicts.keys().cloned())
+        .collect();
+
+    let mut targets = Vec::new();
+    for path_str in paths {
+        let relative_path = relative_path(&repo, path_str)?;
+        let matched: Vec<&String> = tracked.iter().filter(|p| is_under(p, &relative_path)).collect();
+        if matched.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Path did not match any tracked files: {}", path_str),
+            ));
+        }
+        if !recursive && !matched.contains(&&relative_path) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Not removing '{}' recursively without -r", path_str),
+            ));
+        }
+        targets.extend(matched.into_iter().cloned());
+    }
+
+    // Deleting a file whose content isn't committed or staged would lose work.
+    // Removing a conflicted file is how a conflict is resolved as a deletion.
+    if !cached && !force {
+        let head_files = repo.head_files();
+        for path in &targets {
+            let file_path = working_dir.join(path);
+            if !file_path.is_file() || repo.conflicts.contains_key(path) {
+                continue;
+            }
+            let current_hash = utils::calculate_hash_bytes(&fs::read(&file_path)?);
+            let staged_hash = repo.index_hash(path);
+            if staged_hash.as_ref() != Some(&current_hash) || staged_hash.as_ref() != head_files.get(path) {
+                return Err(std::io::Error::other(format!(
+                    "'{}' has changes that are not committed; use --cached to keep the file, or -f to force removal",
+                    path
+                )));
+            }
+        }
+    }
+
+    for path in &targets {
+        repo.stage_removal(path)?;
+        let file_path = working_dir.join(path);
+        if !cached && file_path.is_file() {
+            remove_working_file(&working_dir, &file_path)?;
+        }
+        println!("rm '{}'", path);
+    }
+
+    repo.save()?;
+    Ok(())
+}
+
+// Move or rename a tracked file or directory, carrying its staged content along
+pub fn mv(source: &str, destination: &str, force: bool) -> std::io::Result<()> {
+    let mut repo = Repository::discover()?;
+    let working_dir = repo.working_dir.clone();
+
+    let source = relative_path(&repo, source)?;
+    let mut destination = relative_path(&repo, destination)?;
+    if source.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Cannot move the repository root",
+        ));
+    }
+    let source_path = working_dir.join(&source);
+    if !source_path.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Source does not exist: {}", source),
+        ));
+    }
+
+    // Moving into an existing directory keeps the file name
+    if working_dir.join(&destination).is_dir() {
+        let file_name = Path::new(&source).file_name().unwrap().to_string_lossy().into_owned();
+        destination = if destination.is_empty() { file_name } else { format!("{}/{}", destination, file_name) };
+    }
+    if is_under(&destination, &source) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Cannot move '{}' into itself", source),
+        ));
+    }
+    let destination_path = working_dir.join(&destination);
+    if destination_path.exists() && !(force && destination_path.is_file() && source_path.is_file()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("Destination already exists: {}", destination),
+        ));
+    }
+
+    let staged_tree = repo.staged_tree();
+    let moves: Vec<(String, String)> = staged_tree
+        .keys()
+        .filter(|path| is_under(path, &source))
+        .map(|path| (path.clone(), format!("{}{}", destination, &path[source.len()..])))
+        .collect();
+    if moves.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Not under version control: {}", source),
+        ));
+    }
+
+    if let Some(parent) = destination_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&source_path, &destination_path)?;
+
+    for (old_path, new_path) in &moves {
+        repo.stage_removal(old_path)?;
+        repo.stage_entry(new_path, &staged_tree[old_path]);
+    }
+    repo.save()?;
+
+    println!("Renamed {} -> {}", source, destination);
+    Ok(())
+}
+
+pub fn commit(message: &str, author: Option<&str>) -> std::io::Result<()> {
+    let mut repo = Repository::discover()?;
+    repo.commit(message, author)?;
     println!("Created commit: {}", message);
     Ok(())
 }
 
 pub fn status() -> std::io::Result<()> {
-    let working_dir = env::current_dir()?;
-    let repo = Repository::load(working_dir.clone())?;
+    let repo = Repository::discover()?;
+    let working_dir = repo.working_dir.clone();
 
-    println!("On branch master\n");
+    match repo.head() {
+        Head::Branch(name) => println!("On branch {}\n", name),
+        Head::Detached(id) => println!("HEAD detached at {}\n", &id[..8.min(id.len())]),
+    }
+
+    if let Some(merge_head) = &repo.merge_head {
+        println!("Merging {}", &merge_head[..8]);
+        println!("  (fix conflicts and run \"mini-git commit\")");
+        println!("  (use \"mini-git merge --abort\" to abort the merge)\n");
+    }
+
+    if !repo.conflicts.is_empty() {
+        println!("Unmerged paths:");
+        println!("  (use \"mini-git add <file>...\" to mark resolution)");
+        let mut conflicts: Vec<(&String, &ConflictKind)> = repo.conflicts.iter().collect();
+        conflicts.sort_by_key(|(path, _)| *path);
+        for (path, kind) in conflicts {
+            println!("\t{}: {}", kind.label(), path);
+        }
+        println!();
+    }
 
     // Check staged files
-    if repo.staging.is_empty() {
+    if repo.staging.is_empty() && repo.removed.is_empty() {
         println!("No changes staged for commit");
     } else {
         println!("Changes staged for commit:");
         println!("  (use \"mini-git commit\" to commit the staged changes)");
-        for (path, _) in &repo.staging {
-            println!("\tmodified: {}", path);
+        let head_files = repo.head_files();
+        let renames = repo.staged_renames();
+
+        let mut staged: Vec<&String> = repo.staging.keys().collect();
+        staged.sort();
+        for path in staged {
+            if renames.iter().any(|(_, new_path)| new_path == path) {
+                continue;
+            }
+            let label = if head_files.contains_key(path) { "modified" } else { "new file" };
+            println!("\t{}: {}", label, path);
+        }
+        for (old_path, new_path) in &renames {
+            println!("\trenamed: {} -> {}", old_path, new_path);
+        }
+
+        let mut removed: Vec<&String> = repo.removed.iter().collect();
+        removed.sort();
+        for path in removed {
+            if !renames.iter().any(|(old_path, _)| old_path == path) {
+                println!("\tdeleted: {}", path);
+            }
         }
     }
     println!();
 
-    // Check working directory changes
-    let mut has_changes = false;
-    println!("Changes not staged for commit:");
-    println!("  (use \"mini-git add <file>...\" to stage changes)");
-    
-    // Create a longer-lived HashMap for the last commit files
-    let empty_hashmap = std::collections::HashMap::new();
-    let last_commit_files = repo.commits.last().map(|c| &c.files).unwrap_or(&empty_hashmap);
+    // Check working directory changes
+    let mut has_changes = false;
+    println!("Changes not staged for commit:");
+    println!("  (use \"mini-git add <file>...\" to stage changes)");
+    
+    let last_commit_files = repo.head_files();
+
+    // Tracked files missing from disk whose deletion hasn't been staged
+    for path in last_commit_files.keys() {
+        if !repo.removed.contains(path) && !working_dir.join(path).exists() {
+            println!("\tdeleted: {}", path);
+            has_changes = true;
+        }
+    }
+
+    // Compare with what the next commit would record; paths staged for
+    // removal count as untracked
+    let mut index = Index::load(&repo)?;
+    let staged_tree = repo.staged_tree();
+    for relative_path in working_files(&repo)? {
+        if let Some(staged_hash) = staged_tree.get(&relative_path) {
+            if index.hash(&relative_path)? != *staged_hash {
+                println!("\tmodified: {}", relative_path);
+                has_changes = true;
+            }
+        } else {
+            println!("\tuntracked: {}", relative_path);
+            has_changes = true;
+        }
+    }
+
+    if !has_changes {
+        println!("\tno changes");
+    }
+
+    index.save(&repo)
+}
+
+pub fn history() -> std::io::Result<()> {
+    let repo = Repository::discover()?;
+
+    let head = match repo.head_commit() {
+        Some(commit) => commit,
+        None => {
+            println!("No commits yet");
+            return Ok(());
+        }
+    };
+
+    for commit in repo.walk_history(&head.id) {
+        println!("Commit: {}", &commit.id[..8]);
+        if commit.parents.len() > 1 {
+            let parents: Vec<&str> = commit.parents.iter().map(|p| &p[..8]).collect();
+            println!("Merge: {}", parents.join(" "));
+        }
+        match &commit.author {
+            Some(author) => {
+                println!("Author: {}", author);
+                let committer = commit.committer.as_ref().filter(|c| c.name != author.name || c.email != author.email);
+                if let Some(committer) = committer {
+                    println!("Committer: {}", committer);
+                }
+                println!("Date: {}", author.time.format("%a %b %e %H:%M:%S %Y %z"));
+            }
+            None => println!("Date: {}", commit.timestamp),
+        }
+        println!("Message: {}\n", commit.message);
+    }
+    Ok(())
+}
+
+pub fn remote_add(name: &str, url: &str) -> std::io::Result<()> {
+    Repository::validate_branch_name(name)?;
+    let mut file = config_file(Scope::Local)?;
+    if file.get(&format!("remote.{}.url", name)).is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("Remote already exists: {}", name),
+        ));
+    }
+    file.set(&format!("remote.{}.url", name), url)?;
+    file.save()
+}
+
+pub fn remote_remove(name: &str) -> std::io::Result<()> {
+    let mut file = config_file(Scope::Local)?;
+    if !file.remove_section(&format!("remote.{}", name)) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("No such remote: {}", name),
+        ));
+    }
+    file.save()?;
+
+    let repo = Repository::discover()?;
+    repo.delete_remote_refs(name)
+}
+
+pub fn remote_list(verbose: bool) -> std::io::Result<()> {
+    let repo = Repository::discover()?;
+    for (name, url) in repo.config()?.remotes()? {
+        if verbose {
+            println!("{}\t{}", name, url);
+        } else {
+            println!("{}", name);
+        }
+    }
+    Ok(())
+}
+
+// Open the repository a named remote points at. Relative URLs are taken
+// relative to the working directory.
+fn open_remote(repo: &Repository, name: &str) -> std::io::Result<Repository> {
+    let url = repo.config()?
+        .get(&format!("remote.{}.url", name))
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No such remote: {}", name),
+            )
+        })?;
+    Repository::open(&repo.working_dir.join(url))
+}
+
+// The branch a push or pull works on: the one named, or the current branch
+fn branch_or_current(repo: &Repository, branch: Option<&str>) -> std::io::Result<String> {
+    match branch {
+        Some(branch) => Ok(branch.to_string()),
+        None => repo.current_branch().ok_or_else(|| {
+            std::io::Error::other("HEAD is detached; name the branch to use")
+        }),
+    }
+}
+
+// How push may treat a remote branch that isn't an ancestor of ours
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PushMode {
+    FastForward,
+    // Overwrite only if the remote branch is where we last saw it
+    ForceWithLease,
+    Force,
+}
+
+// Send `branch` and the commits it needs to `remote`, moving the remote branch to match
+pub fn push(remote_name: &str, branch: Option<&str>, mode: PushMode) -> std::io::Result<()> {
+    let repo = Repository::discover()?;
+    let branch = branch_or_current(&repo, branch)?;
+    let tip = repo.read_branch(&branch).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Branch not found: {}", branch),
+        )
+    })?;
+
+    let mut remote = open_remote(&repo, remote_name)?;
+    if !remote.bare && remote.current_branch().as_deref() == Some(branch.as_str()) {
+        return Err(std::io::Error::other(format!(
+            "Refusing to update '{}', it is checked out in {}",
+            branch,
+            remote.working_dir.display()
+        )));
+    }
+    let remote_tip = remote.read_branch(&branch);
+    if remote_tip.as_deref() == Some(tip.as_str()) {
+        repo.write_remote_ref(remote_name, &branch, &tip)?;
+        println!("Everything up-to-date");
+        return Ok(());
+    }
+
+    if mode == PushMode::ForceWithLease && remote_tip != repo.read_remote_ref(remote_name, &branch) {
+        return Err(std::io::Error::other(format!(
+            "Rejected: {}/{} has changed since it was last fetched; fetch and review it first",
+            remote_name, branch
+        )));
+    }
+
+    // Anything the remote branch has that ours doesn't would be lost
+    if let Some(remote_tip) = &remote_tip {
+        let ours = repo.ancestors(&tip);
+        if !ours.contains(remote_tip) && mode == PushMode::FastForward {
+            let lost: Vec<String> = remote
+                .walk_history(remote_tip)
+                .into_iter()
+                .filter(|c| !ours.contains(&c.id))
+                .map(|c| format!("  {} {}", &c.id[..8], c.message.lines().next().unwrap_or("")))
+                .collect();
+            return Err(std::io::Error::other(format!(
+                "Rejected non-fast-forward update of {}/{}; the remote has commits that are not in {}:\n{}\n\
+                 Pull and integrate them first, or use --force to discard them",
+                remote_name,
+                branch,
+                branch,
+                lost.join("\n")
+            )));
+        }
+    }
+
+    let count = remote.import_commits(&repo, &tip)?;
+    remote.save()?;
+    remote.write_branch(&branch, &tip)?;
+    repo.write_remote_ref(remote_name, &branch, &tip)?;
+
+    let forced = remote_tip.as_ref().is_some_and(|old| !repo.is_ancestor(old, &tip));
+    let range = match &remote_tip {
+        Some(old) => format!("{}{}{}", old.get(..8).unwrap_or(old), if forced { "..." } else { ".." }, &tip[..8]),
+        None => format!("new branch {}", &tip[..8]),
+    };
+    println!(
+        "Pushed {} to {} ({}, {} new commits){}",
+        branch,
+        remote_name,
+        range,
+        count,
+        if forced { " (forced update)" } else { "" }
+    );
+    Ok(())
+}
+
+// Create a new working copy of the repository at `source`, with `origin`
+// pointing back at it and its default branch checked out
+pub fn clone(source: &str, destination: Option<&str>) -> std::io::Result<()> {
+    let source_path = fs::canonicalize(source).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("Cannot read {}: {}", source, e))
+    })?;
+    let remote = Repository::open(&source_path)?;
+
+    // Name the copy after the source, or the project holding a bare .mini-git
+    let destination = match destination {
+        Some(destination) => PathBuf::from(destination),
+        None => {
+            let name_source = if source_path.ends_with(".mini-git") {
+                source_path.parent().unwrap_or(&source_path)
+            } else {
+                &source_path
+            };
+            let name = name_source.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            PathBuf::from(name.strip_suffix(".mini-git").unwrap_or(&name))
+        }
+    };
+    if destination.as_os_str().is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Cannot pick a directory name; give the destination explicitly",
+        ));
+    }
+    if destination.exists() && fs::read_dir(&destination)?.next().is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("Destination exists and is not empty: {}", destination.display()),
+        ));
+    }
+
+    println!("Cloning into '{}'...", destination.display());
+    fs::create_dir_all(destination.join(".mini-git"))?;
+    let working_dir = fs::canonicalize(&destination)?;
+    let mut repo = Repository::new(working_dir);
+
+    let branches = remote.list_branches()?;
+    for branch in &branches {
+        if let Some(tip) = remote.read_branch(branch) {
+            repo.import_commits(&remote, &tip)?;
+        }
+    }
+    repo.save()?;
+    for branch in &branches {
+        if let Some(tip) = remote.read_branch(branch) {
+            repo.write_remote_ref("origin", branch, &tip)?;
+        }
+    }
+
+    let mut file = ConfigFile::load(&repo.repo_dir().join("config"))?;
+    file.set("remote.origin.url", &source_path.to_string_lossy())?;
+    file.save()?;
+
+    // Check out whatever the source has checked out
+    let default_branch = remote
+        .current_branch()
+        .filter(|b| branches.contains(b))
+        .or_else(|| branches.first().cloned())
+        .unwrap_or_else(|| DEFAULT_BRANCH.to_string());
+    repo.set_head(&Head::Branch(default_branch.clone()))?;
+    match remote.read_branch(&default_branch) {
+        Some(tip) => {
+            repo.write_branch(&default_branch, &tip)?;
+            let files = repo.find_commit(&tip).ok_or_else(|| missing_commit(&tip))?.files.clone();
+            update_working_tree(&repo, &HashMap::new(), &files)?;
+            println!("Checked out branch '{}' ({} commits)", default_branch, repo.commits.len());
+        }
+        None => println!("Cloned an empty repository"),
+    }
+    Ok(())
+}
+
+// Download what `remote` has into remote-tracking refs, leaving local branches alone
+pub fn fetch(remote_name: &str) -> std::io::Result<()> {
+    let mut repo = Repository::discover()?;
+    fetch_remote(&mut repo, remote_name)
+}
+
+fn fetch_remote(repo: &mut Repository, remote_name: &str) -> std::io::Result<()> {
+    let remote = open_remote(repo, remote_name)?;
+    let branches = remote.list_branches()?;
+
+    let mut count = 0;
+    let mut updates = Vec::new();
+    for branch in &branches {
+        let Some(tip) = remote.read_branch(branch) else {
+            continue;
+        };
+        count += repo.import_commits(&remote, &tip)?;
+        let old = repo.read_remote_ref(remote_name, branch);
+        if old.as_deref() != Some(tip.as_str()) {
+            // Refs are files anyone can edit, so don't trust their length
+            let tip_short = tip.get(..8).unwrap_or(&tip);
+            let range = match &old {
+                Some(old) if repo.is_ancestor(old, &tip) => format!("{}..{}", old.get(..8).unwrap_or(old), tip_short),
+                Some(old) => format!("{}...{} (forced update)", old.get(..8).unwrap_or(old), tip_short),
+                None => "[new branch]".to_string(),
+            };
+            updates.push(format!("  {}  {} -> {}/{}", range, branch, remote_name, branch));
+        }
+    }
+    // Commits have to be recorded before any ref points at them
+    repo.save()?;
+
+    for branch in &branches {
+        if let Some(tip) = remote.read_branch(branch) {
+            repo.write_remote_ref(remote_name, branch, &tip)?;
+        }
+    }
+    for branch in repo.list_remote_refs(remote_name)? {
+        if !branches.contains(&branch) {
+            repo.delete_remote_ref(remote_name, &branch)?;
+            updates.push(format!("  [deleted]  {}/{}", remote_name, branch));
+        }
+    }
+
+    if !updates.is_empty() {
+        println!("From {} ({} new commits)", remote_name, count);
+        updates.iter().for_each(|line| println!("{}", line));
+    }
+    Ok(())
+}
+
+// How pull combines the fetched branch with local work
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PullMode {
+    FastForwardOnly,
+    Merge,
+    Rebase,
+}
+
+// Fetch `remote` and integrate its `branch` into the current branch
+pub fn pull(remote_name: &str, branch: Option<&str>, mode: PullMode) -> std::io::Result<()> {
+    let mut repo = Repository::discover()?;
+    let branch = branch_or_current(&repo, branch)?;
+
+    fetch_remote(&mut repo, remote_name)?;
+    let label = format!("{}/{}", remote_name, branch);
+    let tip = repo.read_remote_ref(remote_name, &branch).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Remote {} has no branch {}", remote_name, branch),
+        )
+    })?;
+
+    let Some(head_id) = repo.head_id() else {
+        // Nothing local yet, so just take the remote branch
+        let files = repo.find_commit(&tip).ok_or_else(|| missing_commit(&tip))?.files.clone();
+        restore_commit_files(&mut repo, &files, false, &format!("pull {}", label))?;
+        repo.advance_head(&tip)?;
+        println!("Checked out {}", label);
+        return Ok(());
+    };
+
+    match mode {
+        PullMode::Merge => merge_into_head(&mut repo, &tip, &label),
+        PullMode::Rebase => rebase_onto(&mut repo, &tip, &label),
+        PullMode::FastForwardOnly => {
+            if !repo.is_ancestor(&head_id, &tip) && !repo.is_ancestor(&tip, &head_id) {
+                return Err(std::io::Error::other(format!(
+                    "Not possible to fast-forward; {} and {} have diverged. Use --merge or --rebase",
+                    branch, label
+                )));
+            }
+            merge_into_head(&mut repo, &tip, &label)
+        }
+    }
+}
+
+// Replay the commits on HEAD that `upstream` lacks on top of it. Everything is
+// computed before the working tree is touched, so a conflict leaves it as it was.
+fn rebase_onto(repo: &mut Repository, upstream: &str, label: &str) -> std::io::Result<()> {
+    if repo.merge_head.is_some() {
+        return Err(std::io::Error::other(
+            "A merge is in progress; commit it or run \"mini-git merge --abort\" first",
+        ));
+    }
+    ensure_clean_working_tree(repo)?;
+
+    let head_id = repo.head_id().unwrap();
+    if repo.is_ancestor(upstream, &head_id) {
+        println!("Already up to date");
+        return Ok(());
+    }
+    if repo.is_ancestor(&head_id, upstream) {
+        return merge_into_head(repo, upstream, label);
+    }
+
+    // Oldest first; merge commits are dropped, their changes come with the replay
+    let upstream_commits = repo.ancestors(upstream);
+    let mut to_replay: Vec<crate::repository::Commit> = repo
+        .walk_history(&head_id)
+        .into_iter()
+        .filter(|c| !upstream_commits.contains(&c.id) && c.parents.len() <= 1)
+        .cloned()
+        .collect();
+    to_replay.reverse();
+
+    let head_files = repo.head_files();
+    let mut current = upstream.to_string();
+    let mut current_files = repo.find_commit(upstream).ok_or_else(|| missing_commit(upstream))?.files.clone();
+    let mut replayed = 0;
+
+    for commit in to_replay {
+        let base_files = commit
+            .parents
+            .first()
+            .and_then(|id| repo.find_commit(id))
+            .map(|c| c.files.clone())
+            .unwrap_or_default();
+        let merged = merge::merge_trees(repo, &base_files, &current_files, &commit.files, label, &commit.id[..8])?;
+
+        let mut files = current_files.clone();
+        let mut conflicts = Vec::new();
+        for (path, result) in merged {
+            match result {
+                MergedFile::Clean(hash) => {
+                    files.insert(path, hash);
+                }
+                MergedFile::Deleted => {
+                    files.remove(&path);
+                }
+                MergedFile::Conflict(..) => conflicts.push(path),
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(std::io::Error::other(format!(
+                "Rebase stopped: {} \"{}\" conflicts with {} in {}. Nothing was changed; use --merge instead",
+                &commit.id[..8],
+                commit.message.lines().next().unwrap_or(""),
+                label,
+                conflicts.join(", ")
+            )));
+        }
+
+        // Changes that are already upstream leave nothing to replay
+        if files == current_files {
+            continue;
+        }
+        current = repo.record_commit(vec![current], &commit.message, files.clone(), commit.author.clone())?;
+        current_files = files;
+        replayed += 1;
+    }
+
+    ensure_untracked_kept(repo, &head_files, &current_files)?;
+    repo.save()?;
+    update_working_tree(repo, &head_files, &current_files)?;
+    repo.advance_head(&current)?;
+    println!("Rebased {} commits onto {}", replayed, label);
+    Ok(())
+}
+
+pub fn checkout(commit_id: &str, force: bool) -> std::io::Result<()> {
+    let mut repo = Repository::discover()?;
+
+    // Checking out a branch attaches HEAD to it
+    if repo.read_branch(commit_id).is_some() {
+        // switch loads the repository itself, so release the lock first
+        drop(repo);
+        return switch(commit_id, false, force);
+    }
+    
+    let commit = repo.get_commit(commit_id)?.clone();
+    
+    restore_commit_files(&mut repo, &commit.files, force, &format!("checkout {}", &commit.id[..8]))?;
+    repo.set_head(&Head::Detached(commit.id.clone()))?;
+    
+    println!("Checked out commit: {} (detached HEAD)", &commit.id[..8]);
+    Ok(())
+}
+
+// Make the working directory and staging match `files`, refusing to lose
+// local changes unless `force` is set
+fn restore_commit_files(
+    repo: &mut Repository,
+    files: &HashMap<String, String>,
+    force: bool,
+    reason: &str,
+) -> std::io::Result<()> {
+    let head_files = repo.head_files();
+
+    if !force {
+        if repo.merge_head.is_some() {
+            return Err(std::io::Error::other(
+                "A merge is in progress; finish it or run \"mini-git merge --abort\" first",
+            ));
+        }
+        let overwritten = overwritten_local_changes(repo, &head_files, files)?;
+        if !overwritten.is_empty() {
+            return Err(std::io::Error::other(format!(
+                "Your local changes would be overwritten: {}\nCommit them, or use --force to discard them",
+                overwritten.join(", ")
+            )));
+        }
+    }
+
+    let retention = repo.config()?.backup_retention()?;
+    let backup = backup::create(repo, &working_files(repo)?, reason, retention)?;
+    println!("Saved working directory as backup {}", backup.id);
+
+    if force {
+        // Rewrite every file so local modifications are discarded too
+        update_working_tree(repo, &head_files, &HashMap::new())?;
+        update_working_tree(repo, &HashMap::new(), files)?;
+    } else {
+        update_working_tree(repo, &head_files, files)?;
+    }
+
+    repo.staging.clear();
+    repo.removed.clear();
+    repo.conflicts.clear();
+    repo.merge_head = None;
+    repo.save()
+}
+
+// Paths whose uncommitted content would be lost by replacing the HEAD tree
+// with `target`: modified or staged tracked files the switch touches, staged
+// changes that disappear with the staging area, and untracked files in the way
+fn overwritten_local_changes(
+    repo: &Repository,
+    head_files: &HashMap<String, String>,
+    target: &HashMap<String, String>,
+) -> std::io::Result<Vec<String>> {
+    let mut overwritten = BTreeSet::new();
+
+    for path in repo.staging.keys().chain(repo.removed.iter()) {
+        if repo.index_hash(path).as_ref() != target.get(path) {
+            overwritten.insert(path.clone());
+        }
+    }
+
+    let paths: BTreeSet<&String> = head_files.keys().chain(target.keys()).collect();
+    for path in paths {
+        let head_hash = head_files.get(path);
+        let target_hash = target.get(path);
+        let file_path = repo.working_dir.join(path);
+        if head_hash == target_hash || !file_path.is_file() {
+            continue;
+        }
+        let current_hash = utils::calculate_hash_bytes(&fs::read(&file_path)?);
+        if Some(&current_hash) != head_hash && Some(&current_hash) != target_hash {
+            overwritten.insert(path.clone());
+        }
+    }
+
+    Ok(overwritten.into_iter().collect())
+}
+
+pub fn switch(name: &str, create: bool, force: bool) -> std::io::Result<()> {
+    let mut repo = Repository::discover()?;
+
+    if create {
+        create_branch(&repo, name, None)?;
+        repo.set_head(&Head::Branch(name.to_string()))?;
+        println!("Switched to a new branch '{}'", name);
+        return Ok(());
+    }
+
+    // A branch that only exists on one remote is created from its tracking ref
+    let (commit_id, tracked_remote) = match repo.read_branch(name) {
+        Some(commit_id) => (commit_id, None),
+        None => {
+            let remotes = repo.config()?.remotes()?;
+            let tracking: Vec<(String, String)> = remotes
+                .into_iter()
+                .filter_map(|(remote, _)| repo.read_remote_ref(&remote, name).map(|id| (remote, id)))
+                .collect();
+            match <[_; 1]>::try_from(tracking) {
+                Ok([(remote, commit_id)]) => (commit_id, Some(remote)),
+                Err(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("Branch not found: {}", name),
+                    ));
+                }
+            }
+        }
+    };
+    let files = repo.find_commit(&commit_id).map(|commit| commit.files.clone()).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Branch {} points to a missing commit", name),
+        )
+    })?;
+
+    restore_commit_files(&mut repo, &files, force, &format!("switch to {}", name))?;
+    if let Some(remote) = &tracked_remote {
+        repo.write_branch(name, &commit_id)?;
+        println!("Created branch '{}' from {}/{}", name, remote, name);
+    }
+    repo.set_head(&Head::Branch(name.to_string()))?;
+    println!("Switched to branch '{}'", name);
+    Ok(())
+}
+
+pub fn branch_list() -> std::io::Result<()> {
+    let repo = Repository::discover()?;
+
+    let head = repo.head();
+    if let Head::Detached(id) = &head {
+        println!("* (HEAD detached at {})", &id[..8.min(id.len())]);
+    }
+    for name in repo.list_branches()? {
+        let marker = if head == Head::Branch(name.clone()) { "*" } else { " " };
+        println!("{} {}", marker, name);
+    }
+    Ok(())
+}
+
+pub fn branch_create(name: &str, start_point: Option<&str>) -> std::io::Result<()> {
+    let repo = Repository::discover()?;
+    let commit_id = create_branch(&repo, name, start_point)?.ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "No commits yet")
+    })?;
+    println!("Created branch {} at {}", name, &commit_id[..8]);
+    Ok(())
+}
+
+// Create `name` at `start_point` (HEAD by default); returns the commit it points
+// to, or None when HEAD is still unborn and no ref file could be written
+fn create_branch(repo: &Repository, name: &str, start_point: Option<&str>) -> std::io::Result<Option<String>> {
+    Repository::validate_branch_name(name)?;
+    if repo.read_branch(name).is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("A branch named '{}' already exists", name),
+        ));
+    }
+
+    let commit_id = match start_point {
+        Some(rev) => Some(repo.get_commit(rev)?.id.clone()),
+        None => repo.head_id(),
+    };
+    if let Some(id) = &commit_id {
+        repo.write_branch(name, id)?;
+    }
+    Ok(commit_id)
+}
+
+pub fn branch_delete(name: &str, force: bool) -> std::io::Result<()> {
+    let repo = Repository::discover()?;
+
+    let commit_id = repo.read_branch(name).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Branch not found: {}", name),
+        )
+    })?;
+    if repo.current_branch().as_deref() == Some(name) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Cannot delete branch '{}' while it is checked out", name),
+        ));
+    }
+    if !force {
+        if let Some(head_id) = repo.head_id() {
+            if !repo.is_ancestor(&commit_id, &head_id) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("The branch '{}' is not fully merged; use -D to delete it anyway", name),
+                ));
+            }
+        }
+    }
+
+    repo.delete_branch(name)?;
+    println!("Deleted branch {} (was {})", name, &commit_id[..8]);
+    Ok(())
+}
+
+// Rename `old_name` (the current branch by default) to `new_name`
+pub fn branch_rename(old_name: Option<&str>, new_name: &str) -> std::io::Result<()> {
+    let repo = Repository::discover()?;
+
+    let current = repo.current_branch();
+    let old_name = match old_name.map(String::from).or_else(|| current.clone()) {
+        Some(name) => name,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "HEAD is detached; specify the branch to rename",
+            ));
+        }
+    };
+    Repository::validate_branch_name(new_name)?;
+    if repo.read_branch(new_name).is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("A branch named '{}' already exists", new_name),
+        ));
+    }
+
+    match repo.read_branch(&old_name) {
+        Some(commit_id) => {
+            repo.write_branch(new_name, &commit_id)?;
+            repo.delete_branch(&old_name)?;
+        }
+        // An unborn current branch has no ref file yet, only HEAD needs updating
+        None if current.as_deref() == Some(old_name.as_str()) => {}
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Branch not found: {}", old_name),
+            ));
+        }
+    }
+    if current.as_deref() == Some(old_name.as_str()) {
+        repo.set_head(&Head::Branch(new_name.to_string()))?;
+    }
+
+    println!("Renamed branch {} to {}", old_name, new_name);
+    Ok(())
+}
+
+// The single file a `config` command with an explicit scope works on
+fn config_file(scope: Scope) -> std::io::Result<ConfigFile> {
+    let repo_dir = match scope {
+        Scope::Local => Some(Repository::discover()?.repo_dir()),
+        _ => None,
+    };
+    let path = scope.path(repo_dir.as_deref()).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Cannot locate the global config; set HOME or XDG_CONFIG_HOME",
+        )
+    })?;
+    ConfigFile::load(&path)
+}
+
+// Every layer that applies here; the repository's own only inside one
+fn current_config() -> std::io::Result<Config> {
+    match Repository::discover() {
+        Ok(repo) => repo.config(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::load(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Print the effective value of `key`; returns false when it isn't set
+pub fn config_get(key: &str, scope: Option<Scope>) -> std::io::Result<bool> {
+    config::normalize_key(key)?;
+    let value = match scope {
+        Some(scope) => config_file(scope)?.get(key),
+        None => current_config()?.get(key),
+    };
+    if let Some(value) = &value {
+        println!("{}", value);
+    }
+    Ok(value.is_some())
+}
+
+pub fn config_set(key: &str, value: &str, scope: Scope) -> std::io::Result<()> {
+    let mut file = config_file(scope)?;
+    file.set(key, value)?;
+    file.save()
+}
+
+pub fn config_unset(key: &str, scope: Scope) -> std::io::Result<()> {
+    let mut file = config_file(scope)?;
+    if !file.unset(key)? {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Config key is not set: {}", key),
+        ));
+    }
+    file.save()
+}
+
+// Every setting, lowest precedence first, so the last occurrence of a key wins
+pub fn config_list(scope: Option<Scope>) -> std::io::Result<()> {
+    let settings = match scope {
+        Some(scope) => config_file(scope)?.list()?,
+        None => current_config()?
+            .list()?
+            .into_iter()
+            .map(|(_, key, value)| (key, value))
+            .collect(),
+    };
+    for (key, value) in settings {
+        println!("{}={}", key, value);
+    }
+    Ok(())
+}
+
+pub fn backups_list() -> std::io::Result<()> {
+    let repo = Repository::discover()?;
+
+    let backups = backup::list(&repo)?;
+    if backups.is_empty() {
+        println!("No backups");
+    }
+    for backup in backups.iter().rev() {
+        println!(
+            "{}  {}  {} files  ({})",
+            backup.id,
+            backup.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            backup.files.len(),
+            backup.reason
+        );
+    }
+    Ok(())
+}
+
+// Write the files saved in a backup back into the working directory. The
+// current contents are backed up first so the restore can be undone too.
+pub fn backups_restore(id: &str) -> std::io::Result<()> {
+    let repo = Repository::discover()?;
+    let working_dir = repo.working_dir.clone();
+
+    let backup = backup::find(&repo, id)?;
+    let retention = repo.config()?.backup_retention()?;
+    let current = backup::create(&repo, &working_files(&repo)?, &format!("restore {}", backup.id), retention)?;
+
+    let mut paths: Vec<&String> = backup.files.keys().collect();
+    paths.sort();
+    for path in paths {
+        write_working_file(&working_dir.join(path), &repo.get_object(&backup.files[path])?)?;
+    }
+
+    println!("Restored {} files from backup {}", backup.files.len(), backup.id);
+    println!("Previous working directory saved as backup {}", current.id);
+    Ok(())
+}
+
+// Check out the tip of the current branch. The newest entry in `commits`
+// may have been fetched from a remote, so it isn't used.
+pub fn loadlast() -> std::io::Result<()> {
+    let repo = Repository::discover()?;
+    
+    if repo.head_id().is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "No commits found on the current branch",
+        ));
+    }
+    
+    let target = match repo.head() {
+        Head::Branch(name) => name,
+        Head::Detached(id) => id,
+    };
+    drop(repo);
+    checkout(&target, false)?;
+    
+    Ok(())
+}
+
+pub fn merge(target: &str) -> std::io::Result<()> {
+    let mut repo = Repository::discover()?;
+    let their_id = repo.get_commit(target)?.id.clone();
+    merge_into_head(&mut repo, &their_id, target)
+}
+
+// Merge commit `their_id` into HEAD, fast-forwarding when possible. `target`
+// names the other side in conflict markers and the merge message.
+fn merge_into_head(repo: &mut Repository, their_id: &str, target: &str) -> std::io::Result<()> {
+    if repo.merge_head.is_some() {
+        return Err(std::io::Error::other(
+            "A merge is already in progress; commit it or run \"mini-git merge --abort\"",
+        ));
+    }
+
+    let (head_id, head_files) = match repo.head_commit() {
+        Some(commit) => (commit.id.clone(), commit.files.clone()),
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "No commits yet",
+            ));
+        }
+    };
+    let their_files = repo.find_commit(their_id).ok_or_else(|| missing_commit(their_id))?.files.clone();
+
+    ensure_clean_working_tree(repo)?;
+
+    if repo.is_ancestor(their_id, &head_id) {
+        println!("Already up to date");
+        return Ok(());
+    }
+
+    if repo.is_ancestor(&head_id, their_id) {
+        ensure_untracked_kept(repo, &head_files, &their_files)?;
+        update_working_tree(repo, &head_files, &their_files)?;
+        repo.advance_head(their_id)?;
+        println!("Fast-forward {}..{}", &head_id[..8], &their_id[..8]);
+        return Ok(());
+    }
+
+    // The merge commit needs both identities; find out now rather than after
+    // the working tree has been rewritten
+    let now = Local::now().fixed_offset();
+    repo.identity("Author", now)?;
+    repo.identity("Committer", now)?;
+
+    // Unrelated histories merge against an empty tree
+    let base_files = repo
+        .merge_base(&head_id, their_id)
+        .and_then(|id| repo.find_commit(&id))
+        .map(|c| c.files.clone())
+        .unwrap_or_default();
+
+    let ours_label = repo.current_branch().unwrap_or_else(|| "HEAD".to_string());
+    let merged = merge::merge_trees(repo, &base_files, &head_files, &their_files, &ours_label, target)?;
+
+    // What the working tree will hold, with conflicted files as written out
+    let mut merged_files = head_files.clone();
+    for (path, result) in &merged {
+        match result {
+            MergedFile::Clean(hash) => merged_files.insert(path.clone(), hash.clone()),
+            MergedFile::Deleted => merged_files.remove(path),
+            MergedFile::Conflict(content, _) => merged_files.insert(path.clone(), utils::calculate_hash_bytes(content)),
+        };
+    }
+    ensure_untracked_kept(repo, &head_files, &merged_files)?;
 
-    for entry in WalkDir::new(&working_dir)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
-    {
-        let path = entry.path();
-        if !path.starts_with(working_dir.join(".mini-git")) {
-            let relative_path = path.strip_prefix(&working_dir).unwrap().to_string_lossy();
-            let content = fs::read(path)?;
-            let current_hash = utils::calculate_hash_bytes(&content);
-
-            // Check if file is modified compared to staging or last commit
-            if let Some(staged_hash) = repo.staging.get(&*relative_path) {
-                if &current_hash != staged_hash {
-                    println!("\tmodified: {}", relative_path);
-                    has_changes = true;
-                }
-            } else if let Some(committed_hash) = last_commit_files.get(&*relative_path) {
-                if &current_hash != committed_hash {
-                    println!("\tmodified: {}", relative_path);
-                    has_changes = true;
+    for (path, result) in merged {
+        let file_path = repo.working_dir.join(&path);
+        match result {
+            MergedFile::Clean(hash) => {
+                write_working_file(&file_path, &repo.get_object(&hash)?)?;
+                repo.staging.insert(path, hash);
+            }
+            MergedFile::Deleted => {
+                if file_path.exists() {
+                    fs::remove_file(&file_path)?;
                 }
-            } else {
-                println!("\tuntracked: {}", relative_path);
-                has_changes = true;
+                repo.removed.insert(path);
+            }
+            MergedFile::Conflict(content, kind) => {
+                write_working_file(&file_path, &content)?;
+                println!("CONFLICT ({}): {}", kind.label(), path);
+                repo.conflicts.insert(path, kind);
             }
         }
     }
 
-    if !has_changes {
-        println!("\tno changes");
+    // Saved before committing, so that if recording the commit fails the
+    // merge can still be concluded with "mini-git commit"
+    repo.merge_head = Some(their_id.to_string());
+    repo.save()?;
+    if repo.conflicts.is_empty() {
+        let message = format!("Merge {} into {}", target, ours_label);
+        repo.commit(&message, None)?;
+        println!("Created merge commit: {}", message);
+    } else {
+        println!("Automatic merge failed; fix conflicts and then commit the result");
     }
-
     Ok(())
 }
 
-pub fn history() -> std::io::Result<()> {
-    let working_dir = env::current_dir()?;
-    let repo = Repository::load(working_dir)?;
+pub fn merge_abort() -> std::io::Result<()> {
+    let mut repo = Repository::discover()?;
 
-    if repo.commits.is_empty() {
-        println!("No commits yet");
-        return Ok(());
+    if repo.merge_head.is_none() {
+        return Err(std::io::Error::other("No merge in progress"));
     }
 
-    for commit in repo.commits.iter().rev() {
-        println!(
-            "Commit: {}\nDate: {}\nMessage: {}\n",
-            &commit.id[..8],
-            commit.timestamp,
-            commit.message
-        );
+    // Put back the HEAD version of everything the merge touched
+    let head_files = repo.head_files();
+    let touched: Vec<String> = repo
+        .staging
+        .keys()
+        .chain(repo.removed.iter())
+        .chain(repo.conflicts.keys())
+        .cloned()
+        .collect();
+    for path in touched {
+        let file_path = repo.working_dir.join(&path);
+        match head_files.get(&path) {
+            Some(hash) => write_working_file(&file_path, &repo.get_object(hash)?)?,
+            None if file_path.exists() => fs::remove_file(&file_path)?,
+            None => {}
+        }
     }
+
+    repo.staging.clear();
+    repo.removed.clear();
+    repo.conflicts.clear();
+    repo.merge_head = None;
+    repo.save()?;
+    println!("Merge aborted");
     Ok(())
 }
 
-pub fn push() -> std::io::Result<()> {
-    let working_dir = env::current_dir()?;
-    let repo = Repository::load(working_dir.clone())?;
-    
-    let remote_dir = working_dir.join(".mini-git/remote");
-    fs::create_dir_all(&remote_dir)?;
-    
-    let remote_repo_file = remote_dir.join("repository.json");
-    let serialized = serde_json::to_string_pretty(&repo)?;
-    fs::write(remote_repo_file, serialized)?;
-    
-    println!("Pushed changes to remote");
+// Refuse to proceed while there are staged changes or modified tracked files
+fn ensure_clean_working_tree(repo: &Repository) -> std::io::Result<()> {
+    if !repo.staging.is_empty() || !repo.removed.is_empty() {
+        return Err(std::io::Error::other(
+            "You have staged changes; commit them before continuing",
+        ));
+    }
+
+    let modified = modified_tracked_files(repo)?;
+    if !modified.is_empty() {
+        return Err(std::io::Error::other(format!(
+            "Your local changes would be overwritten: {}",
+            modified.join(", ")
+        )));
+    }
     Ok(())
 }
 
-pub fn pull() -> std::io::Result<()> {
-    let working_dir = env::current_dir()?;
-    let remote_dir = working_dir.join(".mini-git/remote");
-    let remote_repo_file = remote_dir.join("repository.json");
-    
-    if !remote_repo_file.exists() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            "No remote repository found",
-        ));
+// Refuse to turn the HEAD tree into `target` when that would write over files
+// that aren't tracked. Tracked changes are caught by ensure_clean_working_tree.
+fn ensure_untracked_kept(
+    repo: &Repository,
+    head_files: &HashMap<String, String>,
+    target: &HashMap<String, String>,
+) -> std::io::Result<()> {
+    let overwritten = overwritten_local_changes(repo, head_files, target)?;
+    if !overwritten.is_empty() {
+        return Err(std::io::Error::other(format!(
+            "Untracked working tree files would be overwritten: {}\nMove or remove them first",
+            overwritten.join(", ")
+        )));
     }
-    
-    let content = fs::read_to_string(remote_repo_file)?;
-    let remote_repo: Repository = serde_json::from_str(&content)?;
-    
-    let repo_file = working_dir.join(".mini-git/repository.json");
-    let serialized = serde_json::to_string_pretty(&remote_repo)?;
-    fs::write(repo_file, serialized)?;
-    
-    println!("Pulled changes from remote");
     Ok(())
 }
 
-pub fn checkout(commit_id: &str) -> std::io::Result<()> {
-    let working_dir = env::current_dir()?;
-    let repo = Repository::load(working_dir.clone())?;
-    
-    let commit = match repo.get_commit(commit_id) {
-        Some(commit) => commit,
-        None => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "Commit not found",
-            ));
+// Tracked files whose working copy differs from HEAD, including deleted ones
+fn modified_tracked_files(repo: &Repository) -> std::io::Result<Vec<String>> {
+    let mut modified = Vec::new();
+    for (path, hash) in repo.head_files() {
+        let file_path = repo.working_dir.join(&path);
+        if !file_path.exists() || utils::calculate_hash_bytes(&fs::read(&file_path)?) != hash {
+            modified.push(path);
         }
-    };
-    
-    let backup_dir = working_dir.join(".mini-git/backup");
-    if backup_dir.exists() {
-        fs::remove_dir_all(&backup_dir)?;
     }
-    utils::copy_dir_contents(&working_dir, &backup_dir)?;
-    
-    for (path, content_hash) in &commit.files {
-        let file_path = working_dir.join(path);
-        if let Some(parent) = file_path.parent() {
-            fs::create_dir_all(parent)?;
+    modified.sort();
+    Ok(modified)
+}
+
+// Turn a working tree matching `old_files` into one matching `new_files`
+fn update_working_tree(
+    repo: &Repository,
+    old_files: &HashMap<String, String>,
+    new_files: &HashMap<String, String>,
+) -> std::io::Result<()> {
+    for (path, hash) in new_files {
+        if old_files.get(path) != Some(hash) {
+            write_working_file(&repo.working_dir.join(path), &repo.get_object(hash)?)?;
+        }
+    }
+    for path in old_files.keys() {
+        let file_path = repo.working_dir.join(path);
+        if !new_files.contains_key(path) && file_path.exists() {
+            remove_working_file(&repo.working_dir, &file_path)?;
         }
-        
-        let content = repo.get_object(content_hash)?;
-        fs::write(&file_path, content)?;
     }
-    
-    println!("Checked out commit: {}", &commit.id[..8]);
     Ok(())
 }
 
-pub fn loadlast() -> std::io::Result<()> {
-    let working_dir = env::current_dir()?;
-    let repo = Repository::load(working_dir)?;
-    
-    if repo.commits.is_empty() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            "No commits found in repository",
-        ));
+// Delete a file and any directories it leaves empty, up to the working dir
+fn remove_working_file(working_dir: &Path, file_path: &Path) -> std::io::Result<()> {
+    fs::remove_file(file_path)?;
+    let mut dir = file_path.parent();
+    while let Some(path) = dir {
+        if path == working_dir || fs::remove_dir(path).is_err() {
+            break;
+        }
+        dir = path.parent();
     }
-    
-    let last_commit = repo.commits.last().unwrap();
-    checkout(&last_commit.id)?;
-    
     Ok(())
 }
 
-pub fn diff(commit_id1: Option<&str>, commit_id2: Option<&str>) -> std::io::Result<()> {
-    let working_dir = env::current_dir()?;
-    let repo = Repository::load(working_dir.clone())?;
+fn write_working_file(file_path: &Path, content: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(file_path, content)
+}
+
+// `patch` switches the output to a unified diff with `context` lines around
+// each change, or diff.context from the config when not given
+pub fn diff(commit_id1: Option<&str>, commit_id2: Option<&str>, patch: bool, context: Option<usize>) -> std::io::Result<()> {
+    let repo = Repository::discover()?;
+    let patch_context = match (patch, context) {
+        (false, _) => None,
+        (true, Some(context)) => Some(context),
+        (true, None) => Some(repo.config()?.diff_context()?),
+    };
+    let mut index = Index::load(&repo)?;
 
     if repo.commits.is_empty() {
         return Err(std::io::Error::new(
@@ -291,23 +1562,28 @@ pub fn diff(commit_id1: Option<&str>, commit_id2: Option<&str>) -> std::io::Resu
 
     match (commit_id1, commit_id2) {
         (None, None) => {
-            let last_commit = repo.commits.last().unwrap();
-            compare_with_working_dir(&repo, last_commit)?;
+            let last_commit = repo.head_commit().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, "HEAD does not point to a commit")
+            })?;
+            match patch_context {
+                Some(context) => patch_with_working_dir(&repo, &mut index, last_commit, context)?,
+                None => compare_with_working_dir(&repo, &mut index, last_commit)?,
+            }
         }
         (Some(commit_id), None) => {
-            let commit = repo.get_commit(commit_id).ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::NotFound, "Commit not found")
-            })?;
-            compare_with_working_dir(&repo, commit)?;
+            let commit = repo.get_commit(commit_id)?;
+            match patch_context {
+                Some(context) => patch_with_working_dir(&repo, &mut index, commit, context)?,
+                None => compare_with_working_dir(&repo, &mut index, commit)?,
+            }
         }
         (Some(commit_id1), Some(commit_id2)) => {
-            let commit1 = repo.get_commit(commit_id1).ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::NotFound, "First commit not found")
-            })?;
-            let commit2 = repo.get_commit(commit_id2).ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::NotFound, "Second commit not found")
-            })?;
-            compare_commits(&repo, commit1, commit2)?;
+            let commit1 = repo.get_commit(commit_id1)?;
+            let commit2 = repo.get_commit(commit_id2)?;
+            match patch_context {
+                Some(context) => patch_between_commits(&repo, commit1, commit2, context)?,
+                None => compare_commits(&repo, commit1, commit2)?,
+            }
         }
         (None, Some(_)) => {
             return Err(std::io::Error::new(
@@ -317,12 +1593,147 @@ pub fn diff(commit_id1: Option<&str>, commit_id2: Option<&str>) -> std::io::Resu
         }
     }
 
+    index.save(&repo)
+}
+
+// Apply a unified diff to the working directory, optionally staging the result.
+// `patch_file` may be "-" to read the patch from stdin.
+pub fn apply(patch_file: &str, check: bool, stage: bool, strip: usize) -> std::io::Result<()> {
+    let mut repo = Repository::discover()?;
+    let working_dir = repo.working_dir.clone();
+
+    let mut text = String::new();
+    if patch_file == "-" {
+        std::io::stdin().read_to_string(&mut text)?;
+    } else {
+        text = fs::read_to_string(patch_file)?;
+    }
+
+    let file_patches = patch::parse_patch(&text, strip)?;
+    if file_patches.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "No file changes found in patch",
+        ));
+    }
+
+    let mut failed_hunks = 0;
+    for file_patch in &file_patches {
+        let target = file_patch.new_path.as_deref().or(file_patch.old_path.as_deref()).unwrap();
+        println!("{} file {}", if check { "checking" } else { "patching" }, target);
+
+        let original = match &file_patch.old_path {
+            Some(old_path) => match fs::read(working_dir.join(old_path)) {
+                Ok(content) => match String::from_utf8(content) {
+                    Ok(content) => content,
+                    Err(_) => {
+                        eprintln!("{}: not a text file", old_path);
+                        failed_hunks += file_patch.hunks.len().max(1);
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    eprintln!("{}: {}", old_path, e);
+                    failed_hunks += file_patch.hunks.len().max(1);
+                    continue;
+                }
+            },
+            None if working_dir.join(target).exists() => {
+                eprintln!("{}: already exists in working directory", target);
+                failed_hunks += file_patch.hunks.len().max(1);
+                continue;
+            }
+            None => String::new(),
+        };
+
+        let (patched, results) = patch::apply_hunks(&original, &file_patch.hunks);
+        let mut rejected = Vec::new();
+        for (number, (hunk, result)) in file_patch.hunks.iter().zip(&results).enumerate() {
+            match result {
+                HunkResult::Applied { line, offset, fuzz } if *offset != 0 || *fuzz != 0 => {
+                    let mut note = format!("Hunk #{} succeeded at {}", number + 1, line);
+                    if *offset != 0 {
+                        note.push_str(&format!(" (offset {} lines)", offset));
+                    }
+                    if *fuzz != 0 {
+                        note.push_str(&format!(" with fuzz {}", fuzz));
+                    }
+                    println!("{}.", note);
+                }
+                HunkResult::Applied { .. } => {}
+                HunkResult::Rejected => {
+                    println!("Hunk #{} FAILED at {}.", number + 1, hunk.old_start);
+                    rejected.push(hunk);
+                }
+            }
+        }
+        failed_hunks += rejected.len();
+
+        if check {
+            continue;
+        }
+
+        if !rejected.is_empty() {
+            let reject_file = format!("{}.rej", target);
+            let old_name = file_patch.old_path.as_ref().map_or("/dev/null".to_string(), |p| format!("a/{}", p));
+            let new_name = file_patch.new_path.as_ref().map_or("/dev/null".to_string(), |p| format!("b/{}", p));
+            write_working_file(&working_dir.join(&reject_file), patch::format_hunks(&old_name, &new_name, &rejected).as_bytes())?;
+            println!(
+                "{} out of {} hunks FAILED -- saving rejects to file {}",
+                rejected.len(),
+                file_patch.hunks.len(),
+                reject_file
+            );
+        }
+
+        match (&file_patch.old_path, &file_patch.new_path) {
+            (_, Some(new_path)) => {
+                write_working_file(&working_dir.join(new_path), patched.as_bytes())?;
+                if let Some(old_path) = file_patch.old_path.as_ref().filter(|p| *p != new_path) {
+                    fs::remove_file(working_dir.join(old_path))?;
+                }
+            }
+            (Some(old_path), None) if rejected.is_empty() => {
+                if patched.is_empty() {
+                    fs::remove_file(working_dir.join(old_path))?;
+                } else {
+                    eprintln!("{}: not empty after patch, not deleting", old_path);
+                }
+            }
+            _ => {}
+        }
+
+        // Files with rejects are left for the user to fix up before staging
+        if stage && rejected.is_empty() {
+            if let Some(old_path) = &file_patch.old_path {
+                if file_patch.new_path.as_ref() != Some(old_path) && !working_dir.join(old_path).exists() {
+                    repo.stage_removal(old_path)?;
+                }
+            }
+            if let Some(new_path) = &file_patch.new_path {
+                repo.stage_file(&working_dir.join(new_path))?;
+            }
+        }
+    }
+
+    if stage && !check {
+        repo.save()?;
+    }
+
+    if failed_hunks > 0 {
+        return Err(std::io::Error::other(format!("{} hunk(s) could not be applied", failed_hunks)));
+    }
+    if check {
+        println!("Patch applies cleanly");
+    } else {
+        println!("Patch applied successfully");
+    }
     Ok(())
 }
 
 pub fn diffdetailed(commit_id1: Option<&str>, commit_id2: Option<&str>) -> std::io::Result<()> {
-    let working_dir = env::current_dir()?;
-    let repo = Repository::load(working_dir.clone())?;
+    let repo = Repository::discover()?;
+    let mut index = Index::load(&repo)?;
 
     if repo.commits.is_empty() {
         return Err(std::io::Error::new(
@@ -333,22 +1744,18 @@ pub fn diffdetailed(commit_id1: Option<&str>, commit_id2: Option<&str>) -> std::
 
     match (commit_id1, commit_id2) {
         (None, None) => {
-            let last_commit = repo.commits.last().unwrap();
-            compare_with_working_dir_detailed(&repo, last_commit)?;
+            let last_commit = repo.head_commit().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, "HEAD does not point to a commit")
+            })?;
+            compare_with_working_dir_detailed(&repo, &mut index, last_commit)?;
         }
         (Some(commit_id), None) => {
-            let commit = repo.get_commit(commit_id).ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::NotFound, "Commit not found")
-            })?;
-            compare_with_working_dir_detailed(&repo, commit)?;
+            let commit = repo.get_commit(commit_id)?;
+            compare_with_working_dir_detailed(&repo, &mut index, commit)?;
         }
         (Some(commit_id1), Some(commit_id2)) => {
-            let commit1 = repo.get_commit(commit_id1).ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::NotFound, "First commit not found")
-            })?;
-            let commit2 = repo.get_commit(commit_id2).ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::NotFound, "Second commit not found")
-            })?;
+            let commit1 = repo.get_commit(commit_id1)?;
+            let commit2 = repo.get_commit(commit_id2)?;
             compare_commits_detailed(&repo, commit1, commit2)?;
         }
         (None, Some(_)) => {
@@ -359,50 +1766,28 @@ pub fn diffdetailed(commit_id1: Option<&str>, commit_id2: Option<&str>) -> std::
         }
     }
 
-    Ok(())
+    index.save(&repo)
 }
 
-fn compare_with_working_dir_detailed(repo: &Repository, commit: &crate::repository::Commit) -> std::io::Result<()> {
-    let working_dir = env::current_dir()?;
+fn compare_with_working_dir_detailed(
+    repo: &Repository,
+    index: &mut Index,
+    commit: &crate::repository::Commit,
+) -> std::io::Result<()> {
+    let working_dir = &repo.working_dir;
     println!("Comparing working directory with commit {} ({})", &commit.id[..8], commit.message);
     println!("----------------------------------------");
 
     for (path, commit_hash) in &commit.files {
         let file_path = working_dir.join(path);
         if file_path.exists() {
-            let current_content = fs::read(&file_path)?;
-            let current_hash = utils::calculate_hash_bytes(&current_content);
-            
-            if &current_hash != commit_hash {
+            if &index.hash(path)? != commit_hash {
                 println!("Modified: {}", path);
                 
                 // Get both contents and compare them
+                let current_content = fs::read(&file_path)?;
                 let commit_content = repo.get_object(commit_hash)?;
-                let commit_str = String::from_utf8_lossy(&commit_content);
-                let current_str = String::from_utf8_lossy(&current_content);
-                
-                let commit_lines: Vec<&str> = commit_str.lines().collect();
-                let current_lines: Vec<&str> = current_str.lines().collect();
-                
-                // Compare lines and show differences
-                for (i, (old_line, new_line)) in commit_lines.iter().zip(current_lines.iter()).enumerate() {
-                    if old_line != new_line {
-                        println!("Line {}: changed from '{}' to '{}'", i + 1, old_line, new_line);
-                    }
-                }
-                
-                // Show added/removed lines
-                if commit_lines.len() != current_lines.len() {
-                    if commit_lines.len() < current_lines.len() {
-                        for i in commit_lines.len()..current_lines.len() {
-                            println!("Line {}: added '{}'", i + 1, current_lines[i]);
-                        }
-                    } else {
-                        for i in current_lines.len()..commit_lines.len() {
-                            println!("Line {}: removed '{}'", i + 1, commit_lines[i]);
-                        }
-                    }
-                }
+                print_line_changes(&commit_content, &current_content);
                 println!();
             }
         } else {
@@ -417,21 +1802,13 @@ fn compare_with_working_dir_detailed(repo: &Repository, commit: &crate::reposito
     }
 
     // Check for new files
-    for entry in WalkDir::new(&working_dir)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
-    {
-        let path = entry.path();
-        if !path.starts_with(working_dir.join(".mini-git")) {
-            let relative_path = path.strip_prefix(&working_dir).unwrap().to_string_lossy();
-            if !commit.files.contains_key(&*relative_path) {
-                println!("New file: {}", relative_path);
-                if let Ok(content) = fs::read(path) {
-                    println!("New content:");
-                    println!("{}", String::from_utf8_lossy(&content));
-                    println!();
-                }
+    for relative_path in working_files(repo)? {
+        if !commit.files.contains_key(&relative_path) {
+            println!("New file: {}", relative_path);
+            if let Ok(content) = fs::read(working_dir.join(&relative_path)) {
+                println!("New content:");
+                println!("{}", String::from_utf8_lossy(&content));
+                println!();
             }
         }
     }
@@ -439,6 +1816,54 @@ fn compare_with_working_dir_detailed(repo: &Repository, commit: &crate::reposito
     Ok(())
 }
 
+// Print line level changes between two versions of a file. A deletion directly
+// followed by an insertion is reported as changed lines.
+fn print_line_changes(old_content: &[u8], new_content: &[u8]) {
+    let old_str = String::from_utf8_lossy(old_content);
+    let new_str = String::from_utf8_lossy(new_content);
+    let old_lines: Vec<&str> = old_str.lines().collect();
+    let new_lines: Vec<&str> = new_str.lines().collect();
+
+    let hunks = diff::diff_lines(&old_lines, &new_lines);
+    let mut index = 0;
+    while index < hunks.len() {
+        let hunk = &hunks[index];
+        match hunk.op {
+            DiffOp::Equal => {}
+            DiffOp::Delete => {
+                let replacement = hunks.get(index + 1).filter(|h| h.op == DiffOp::Insert);
+                let paired = replacement.map_or(0, |r| r.new.len().min(hunk.old.len()));
+
+                if let Some(replacement) = replacement {
+                    for offset in 0..paired {
+                        println!(
+                            "Line {}: changed from '{}' to '{}'",
+                            replacement.new.start + offset + 1,
+                            old_lines[hunk.old.start + offset],
+                            new_lines[replacement.new.start + offset]
+                        );
+                    }
+                }
+                for (line_index, line) in old_lines.iter().enumerate().take(hunk.old.end).skip(hunk.old.start + paired) {
+                    println!("Line {}: removed '{}'", line_index + 1, line);
+                }
+                if let Some(replacement) = replacement {
+                    for (line_index, line) in new_lines.iter().enumerate().take(replacement.new.end).skip(replacement.new.start + paired) {
+                        println!("Line {}: added '{}'", line_index + 1, line);
+                    }
+                    index += 1;
+                }
+            }
+            DiffOp::Insert => {
+                for (line_index, line) in new_lines.iter().enumerate().take(hunk.new.end).skip(hunk.new.start) {
+                    println!("Line {}: added '{}'", line_index + 1, line);
+                }
+            }
+        }
+        index += 1;
+    }
+}
+
 fn compare_commits_detailed(repo: &Repository, commit1: &crate::repository::Commit, commit2: &crate::repository::Commit) -> std::io::Result<()> {
     println!(
         "Comparing commit {} ({}) with {} ({})",
@@ -458,31 +1883,7 @@ fn compare_commits_detailed(repo: &Repository, commit1: &crate::repository::Comm
                 // Get both contents and compare them
                 let content1 = repo.get_object(hash1)?;
                 let content2 = repo.get_object(hash2)?;
-                let str1 = String::from_utf8_lossy(&content1);
-                let str2 = String::from_utf8_lossy(&content2);
-                
-                let lines1: Vec<&str> = str1.lines().collect();
-                let lines2: Vec<&str> = str2.lines().collect();
-                
-                // Compare lines and show differences
-                for (i, (line1, line2)) in lines1.iter().zip(lines2.iter()).enumerate() {
-                    if line1 != line2 {
-                        println!("Line {}: changed from '{}' to '{}'", i + 1, line1, line2);
-                    }
-                }
-                
-                // Show added/removed lines
-                if lines1.len() != lines2.len() {
-                    if lines1.len() < lines2.len() {
-                        for i in lines1.len()..lines2.len() {
-                            println!("Line {}: added '{}'", i + 1, lines2[i]);
-                        }
-                    } else {
-                        for i in lines2.len()..lines1.len() {
-                            println!("Line {}: removed '{}'", i + 1, lines1[i]);
-                        }
-                    }
-                }
+                print_line_changes(&content1, &content2);
                 println!();
             }
             None => {
@@ -513,8 +1914,270 @@ fn compare_commits_detailed(repo: &Repository, commit1: &crate::repository::Comm
     Ok(())
 }
 
-fn compare_with_working_dir(_: &Repository, commit: &crate::repository::Commit) -> std::io::Result<()> {
-    let working_dir = env::current_dir()?;
+// For commit IDs taken from refs, which may name commits that were never recorded
+fn missing_commit(commit_id: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("Commit not found: {}", commit_id),
+    )
+}
+
+// Files in the working directory relative to its root: everything not matched
+// by .mini-gitignore, plus tracked files which stay visible even when ignored
+fn working_files(repo: &Repository) -> std::io::Result<Vec<String>> {
+    walk_working_dir(repo, false)
+}
+
+// Like `working_files`, but with `include_ignored` nothing is left out
+fn walk_working_dir(repo: &Repository, include_ignored: bool) -> std::io::Result<Vec<String>> {
+    let working_dir = &repo.working_dir;
+    let rules = IgnoreRules::load(working_dir)?;
+    let relative = |path: &Path| {
+        path.strip_prefix(working_dir)
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+
+    let mut files: BTreeSet<String> = WalkDir::new(working_dir)
+        .into_iter()
+        .filter_entry(|e| {
+            let relative_path = relative(e.path());
+            relative_path.is_empty()
+                || (e.file_name() != ".mini-git"
+                    && (include_ignored || !rules.is_ignored(&relative_path, e.file_type().is_dir())))
+        })
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_file())
+        .map(|e| relative(e.path()))
+        .collect();
+
+    let tracked = repo.head_files().into_keys().chain(repo.staging.keys().cloned());
+    files.extend(tracked.filter(|path| working_dir.join(path).is_file()));
+    Ok(files.into_iter().collect())
+}
+
+// Verify the object store, the commit graph and refs. Every stored object is
+// rehashed; referenced objects that are missing or corrupt, broken parent
+// links and refs to unknown commits are problems, while unreferenced
+// (dangling) objects are only reported. With `repair`, bad objects are
+// restored from working tree files that still hash to them. Returns whether
+// no problems remain.
+pub fn fsck(repair: bool) -> std::io::Result<bool> {
+    let repo = Repository::discover()?;
+    let short = |id: &str| id[..8.min(id.len())].to_string();
+    let mut problems = 0;
+
+    // The same references gc keeps objects for
+    let references = gc::references(&repo)?;
+
+    let check = gc::check_objects(&repo, &references)?;
+    for (hash, actual) in &check.corrupt {
+        println!("corrupt object {} (content hashes to {})", hash, actual);
+    }
+    let mut corrupt: BTreeSet<&String> = check.corrupt.keys().collect();
+
+    for (hash, used_by) in &references {
+        let missing = check.missing.contains(hash);
+        if !missing && !corrupt.contains(hash) {
+            continue;
+        }
+        if missing {
+            for (what, path) in used_by {
+                println!("missing object {} ({}: {})", hash, what, path);
+            }
+        }
+        corrupt.remove(hash);
+        if repair {
+            let mut recovered_from = None;
+            for (_, path) in used_by {
+                if repo.recover_object(hash, path)? {
+                    recovered_from = Some(path);
+                    break;
+                }
+            }
+            if let Some(path) = recovered_from {
+                println!("repaired object {} from {}", hash, path);
+                continue;
+            }
+            println!("could not repair object {}: no working tree file has its content", hash);
+        }
+        problems += 1;
+    }
+    // Corrupt objects nothing refers to can't be recovered, but are still damage
+    problems += corrupt.len();
+
+    for hash in &check.dangling {
+        println!("dangling object {}", hash);
+    }
+
+    let ids: HashSet<&str> = repo.commits.iter().map(|c| c.id.as_str()).collect();
+    for commit in &repo.commits {
+        if commit.compute_id() != commit.id {
+            println!("commit {}: ID does not match its content", short(&commit.id));
+            problems += 1;
+        }
+        for parent in &commit.parents {
+            if !ids.contains(parent.as_str()) {
+                println!("commit {}: parent {} not found", short(&commit.id), parent);
+                problems += 1;
+            }
+        }
+    }
+
+    for (name, target) in repo.refs()? {
+        match target {
+            Some(id) if ids.contains(id.as_str()) => {}
+            Some(id) => {
+                println!("{}: points to unknown commit {}", name, id);
+                problems += 1;
+            }
+            None => {
+                println!("{}: empty or unreadable", name);
+                problems += 1;
+            }
+        }
+    }
+
+    println!("Checked {} objects and {} commits", check.stored, repo.commits.len());
+    if problems == 0 {
+        println!("No problems found");
+    } else {
+        println!("{} problems found", problems);
+        if !repair {
+            println!("  (use \"mini-git fsck --repair\" to restore objects from the working tree)");
+        }
+    }
+    Ok(problems == 0)
+}
+
+// Delete objects unreachable from refs, the staging area and backups once
+// they are older than `grace_days`, or gc.graceDays from the config when not
+// given. `dry_run` only reports what would go.
+pub fn gc(dry_run: bool, grace_days: Option<usize>) -> std::io::Result<()> {
+    let repo = Repository::discover()?;
+    let grace_days = match grace_days {
+        Some(days) => days,
+        None => repo.config()?.gc_grace_days()?,
+    };
+    let grace = Duration::from_secs(grace_days as u64 * 24 * 60 * 60);
+
+    let sweep = gc::find_garbage(&repo, grace)?;
+    let bytes: u64 = sweep.garbage.iter().map(|item| item.size).sum();
+    if dry_run {
+        for item in &sweep.garbage {
+            println!("Would remove {} ({} bytes)", item.path.display(), item.size);
+        }
+        println!("{} unreachable objects, {} bytes reclaimable", sweep.garbage.len(), bytes);
+    } else {
+        gc::remove(&repo, &sweep.garbage)?;
+        println!("Removed {} unreachable objects, freed {} bytes", sweep.garbage.len(), bytes);
+    }
+    if sweep.recent > 0 {
+        println!(
+            "Kept {} unreachable objects written in the last {} days (use --grace-days 0 to remove them)",
+            sweep.recent, grace_days
+        );
+    }
+    Ok(())
+}
+
+// Report which of `paths` are ignored; returns whether any of them was
+pub fn check_ignore(paths: &[String], verbose: bool) -> std::io::Result<bool> {
+    let repo = Repository::discover()?;
+    let working_dir = &repo.working_dir;
+    let rules = IgnoreRules::load(working_dir)?;
+    let mut any_ignored = false;
+
+    for path_str in paths {
+        let relative_path = relative_path(&repo, path_str)?;
+        let is_dir = working_dir.join(&relative_path).is_dir();
+        let pattern = rules.matching_pattern(&relative_path, is_dir);
+        let ignored = rules.is_ignored(&relative_path, is_dir);
+        any_ignored |= ignored;
+
+        match pattern {
+            // Verbose mode also shows negated patterns that un-ignore a path
+            Some(pattern) if verbose => {
+                println!("{}:{}:{}\t{}", IGNORE_FILE, pattern.line, pattern.text, path_str);
+            }
+            _ if ignored => println!("{}", path_str),
+            _ => {}
+        }
+    }
+    Ok(any_ignored)
+}
+
+fn patch_with_working_dir(
+    repo: &Repository,
+    index: &mut Index,
+    commit: &crate::repository::Commit,
+    context: usize,
+) -> std::io::Result<()> {
+    let mut paths: BTreeSet<String> = commit.files.keys().cloned().collect();
+    paths.extend(working_files(repo)?);
+
+    for path in paths {
+        let file_path = repo.working_dir.join(&path);
+        let old = match commit.files.get(&path) {
+            // Unchanged files produce no patch, so skip reading them
+            Some(hash) if file_path.is_file() && index.hash(&path)? == *hash => continue,
+            Some(hash) => Some(repo.get_object(hash)?),
+            None => None,
+        };
+        let new = if file_path.is_file() { Some(fs::read(&file_path)?) } else { None };
+        print!("{}", file_patch(&path, old.as_deref(), new.as_deref(), context));
+    }
+    Ok(())
+}
+
+fn patch_between_commits(
+    repo: &Repository,
+    commit1: &crate::repository::Commit,
+    commit2: &crate::repository::Commit,
+    context: usize,
+) -> std::io::Result<()> {
+    let paths: BTreeSet<&String> = commit1.files.keys().chain(commit2.files.keys()).collect();
+
+    for path in paths {
+        let hash1 = commit1.files.get(path);
+        let hash2 = commit2.files.get(path);
+        if hash1 == hash2 {
+            continue;
+        }
+        let old = match hash1 {
+            Some(hash) => Some(repo.get_object(hash)?),
+            None => None,
+        };
+        let new = match hash2 {
+            Some(hash) => Some(repo.get_object(hash)?),
+            None => None,
+        };
+        print!("{}", file_patch(path, old.as_deref(), new.as_deref(), context));
+    }
+    Ok(())
+}
+
+// Unified diff for one file; binary content is only reported as differing
+fn file_patch(path: &str, old: Option<&[u8]>, new: Option<&[u8]>, context: usize) -> String {
+    if old == new {
+        return String::new();
+    }
+    if old.is_some_and(utils::is_binary) || new.is_some_and(utils::is_binary) {
+        let old_name = old.map_or("/dev/null".to_string(), |_| format!("a/{}", path));
+        let new_name = new.map_or("/dev/null".to_string(), |_| format!("b/{}", path));
+        return format!(
+            "diff --git a/{} b/{}\nBinary files {} and {} differ\n",
+            path, path, old_name, new_name
+        );
+    }
+
+    let old_text = old.map(String::from_utf8_lossy);
+    let new_text = new.map(String::from_utf8_lossy);
+    diff::unified_diff(old_text.as_deref(), new_text.as_deref(), path, context)
+}
+
+fn compare_with_working_dir(repo: &Repository, index: &mut Index, commit: &crate::repository::Commit) -> std::io::Result<()> {
+    let working_dir = &repo.working_dir;
     println!("Comparing working directory with commit {} ({})", &commit.id[..8], commit.message);
     println!("----------------------------------------");
 
@@ -522,10 +2185,7 @@ fn compare_with_working_dir(_: &Repository, commit: &crate::repository::Commit)
     for (path, commit_hash) in &commit.files {
         let file_path = working_dir.join(path);
         if file_path.exists() {
-            let current_content = fs::read(&file_path)?;
-            let current_hash = utils::calculate_hash_bytes(&current_content);
-            
-            if &current_hash != commit_hash {
+            if &index.hash(path)? != commit_hash {
                 println!("Modified: {}", path);
             }
         } else {
@@ -534,17 +2194,9 @@ fn compare_with_working_dir(_: &Repository, commit: &crate::repository::Commit)
     }
 
     // Check for new files
-    for entry in WalkDir::new(&working_dir)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
-    {
-        let path = entry.path();
-        if !path.starts_with(working_dir.join(".mini-git")) {
-            let relative_path = path.strip_prefix(&working_dir).unwrap().to_string_lossy();
-            if !commit.files.contains_key(&*relative_path) {
-                println!("New file: {}", relative_path);
-            }
+    for relative_path in working_files(repo)? {
+        if !commit.files.contains_key(&relative_path) {
+            println!("New file: {}", relative_path);
         }
     }
 
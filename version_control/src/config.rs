@@ -0,0 +1,469 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::backup;
+use crate::diff;
+use crate::gc;
+use crate::utils;
+
+// Settings come from INI-style files, keyed as "section.key" or
+// "section.subsection.key":
+//
+//     [user]
+//         name = Jane Doe
+//     [remote "origin"]
+//         url = ../shared
+//
+// Section and key names are case-insensitive, subsections are not.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    System,
+    Global,
+    Local,
+}
+
+impl Scope {
+    // Where this layer lives; None when it can't be located (no home
+    // directory, or no repository for the local layer)
+    pub fn path(&self, repo_dir: Option<&Path>) -> Option<PathBuf> {
+        match self {
+            Scope::System => Some(
+                env::var_os("MINI_GIT_CONFIG_SYSTEM")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| PathBuf::from("/etc/mini-git/config")),
+            ),
+            Scope::Global => env::var_os("XDG_CONFIG_HOME")
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+                .map(|dir| dir.join("mini-git/config")),
+            Scope::Local => repo_dir.map(|dir| dir.join("config")),
+        }
+    }
+}
+
+// One config file. Lines are kept as read so edits leave comments and layout alone.
+#[derive(Debug)]
+pub struct ConfigFile {
+    path: PathBuf,
+    lines: Vec<String>,
+}
+
+// A parsed setting line
+struct Entry {
+    line: usize,
+    key: String,
+    value: String,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let file = ConfigFile {
+            path: path.to_path_buf(),
+            lines: content.lines().map(String::from).collect(),
+        };
+        // Surface syntax errors at load time rather than on first lookup
+        file.entries()?;
+        Ok(file)
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut content = self.lines.join("\n");
+        content.push('\n');
+        utils::write_file_atomic(&self.path, content.as_bytes())
+    }
+
+    fn entries(&self) -> std::io::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        let mut section: Option<String> = None;
+
+        for (index, line) in self.lines.iter().enumerate() {
+            let invalid = |message: &str| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", self.path.display(), index + 1, message),
+                )
+            };
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') {
+                let header = line.strip_prefix('[').and_then(|l| l.strip_suffix(']'));
+                section = Some(header.and_then(parse_section).ok_or_else(|| invalid("invalid section header"))?);
+                continue;
+            }
+
+            let section = section.as_ref().ok_or_else(|| invalid("setting outside of a section"))?;
+            let (name, value) = line.split_once('=').ok_or_else(|| invalid("expected key = value"))?;
+            let name = name.trim();
+            if !valid_name(name) {
+                return Err(invalid("invalid key name"));
+            }
+            entries.push(Entry {
+                line: index,
+                key: format!("{}.{}", section, name.to_lowercase()),
+                value: unquote(value.trim()),
+            });
+        }
+        Ok(entries)
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let key = normalize_key(key).ok()?;
+        let entries = self.entries().ok()?;
+        entries.into_iter().rev().find(|e| e.key == key).map(|e| e.value)
+    }
+
+    // Every setting in file order, including repeated keys
+    pub fn list(&self) -> std::io::Result<Vec<(String, String)>> {
+        Ok(self.entries()?.into_iter().map(|e| (e.key, e.value)).collect())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> std::io::Result<()> {
+        let key = normalize_key(key)?;
+        let (section, name) = key.rsplit_once('.').unwrap();
+        let setting = format!("\t{} = {}", name, quote(value));
+        let entries = self.entries()?;
+
+        // Replace the effective value in place
+        if let Some(entry) = entries.iter().rev().find(|e| e.key == key) {
+            self.lines[entry.line] = setting;
+            return Ok(());
+        }
+
+        // Otherwise add it to the end of an existing section, or start one
+        let last_in_section = self.section_end(section);
+        match last_in_section {
+            Some(line) => self.lines.insert(line + 1, setting),
+            None => {
+                self.lines.push(format_section(section));
+                self.lines.push(setting);
+            }
+        }
+        Ok(())
+    }
+
+    // Remove every occurrence of `key`; returns whether there was any
+    pub fn unset(&mut self, key: &str) -> std::io::Result<bool> {
+        let key = normalize_key(key)?;
+        let lines: Vec<usize> = self.entries()?.into_iter().filter(|e| e.key == key).map(|e| e.line).collect();
+        for &line in lines.iter().rev() {
+            self.lines.remove(line);
+        }
+        Ok(!lines.is_empty())
+    }
+
+    // Remove every `[section]` block with its settings; returns whether there was any
+    pub fn remove_section(&mut self, section: &str) -> bool {
+        let mut current: Option<String> = None;
+        let before = self.lines.len();
+        self.lines.retain(|line| {
+            let trimmed = line.trim();
+            if let Some(header) = trimmed.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                current = parse_section(header);
+            }
+            current.as_deref() != Some(section)
+        });
+        self.lines.len() != before
+    }
+
+    // Index of the last line belonging to the final `[section]` block
+    fn section_end(&self, section: &str) -> Option<usize> {
+        let mut current: Option<String> = None;
+        let mut end = None;
+        for (index, line) in self.lines.iter().enumerate() {
+            let trimmed = line.trim();
+            if let Some(header) = trimmed.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                current = parse_section(header);
+                if current.as_deref() == Some(section) {
+                    end = Some(index);
+                }
+            } else if current.as_deref() == Some(section) && !trimmed.is_empty() {
+                end = Some(index);
+            }
+        }
+        end
+    }
+}
+
+// All layers, lowest precedence first: system, then global, then the repository
+#[derive(Debug)]
+pub struct Config {
+    files: Vec<(Scope, ConfigFile)>,
+}
+
+impl Config {
+    // Outside of a repository `repo_dir` is None and only the system and global layers apply
+    pub fn load(repo_dir: Option<&Path>) -> std::io::Result<Self> {
+        let mut files = Vec::new();
+        for scope in [Scope::System, Scope::Global, Scope::Local] {
+            if let Some(path) = scope.path(repo_dir) {
+                files.push((scope, ConfigFile::load(&path)?));
+            }
+        }
+        Ok(Config { files })
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.files.iter().rev().find_map(|(_, file)| file.get(key))
+    }
+
+    // Effective settings with the scope each came from, in precedence order
+    pub fn list(&self) -> std::io::Result<Vec<(Scope, String, String)>> {
+        let mut settings = Vec::new();
+        for (scope, file) in &self.files {
+            settings.extend(file.list()?.into_iter().map(|(key, value)| (*scope, key, value)));
+        }
+        Ok(settings)
+    }
+
+    pub fn get_usize(&self, key: &str) -> std::io::Result<Option<usize>> {
+        self.get(key)
+            .map(|value| {
+                value.parse().map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Config {} must be a non-negative integer, got: {}", key, value),
+                    )
+                })
+            })
+            .transpose()
+    }
+
+    // Configured remotes as (name, url), sorted by name
+    pub fn remotes(&self) -> std::io::Result<Vec<(String, String)>> {
+        let mut remotes: Vec<(String, String)> = Vec::new();
+        for (_, key, value) in self.list()? {
+            let Some(name) = key.strip_prefix("remote.").and_then(|k| k.strip_suffix(".url")) else {
+                continue;
+            };
+            match remotes.iter_mut().find(|(n, _)| n == name) {
+                Some(remote) => remote.1 = value,
+                None => remotes.push((name.to_string(), value)),
+            }
+        }
+        remotes.sort();
+        Ok(remotes)
+    }
+
+    // Context lines around changes in unified diffs
+    pub fn diff_context(&self) -> std::io::Result<usize> {
+        Ok(self.get_usize("diff.context")?.unwrap_or(diff::DEFAULT_CONTEXT))
+    }
+
+    // Days unreachable objects are kept before gc deletes them
+    pub fn gc_grace_days(&self) -> std::io::Result<usize> {
+        Ok(self.get_usize("gc.graceDays")?.unwrap_or(gc::DEFAULT_GRACE_DAYS))
+    }
+
+    // Working directory backups kept before the oldest are pruned
+    pub fn backup_retention(&self) -> std::io::Result<usize> {
+        match self.get_usize("backup.retention")? {
+            Some(0) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Config backup.retention must be at least 1",
+            )),
+            retention => Ok(retention.unwrap_or(backup::DEFAULT_RETENTION)),
+        }
+    }
+}
+
+// Canonical "section[.subsection]" for a header like `remote "origin"`
+fn parse_section(header: &str) -> Option<String> {
+    let header = header.trim();
+    match header.split_once(char::is_whitespace) {
+        Some((name, subsection)) => {
+            let subsection = subsection.trim().strip_prefix('"')?.strip_suffix('"')?;
+            (valid_name(name) && !subsection.is_empty()).then(|| format!("{}.{}", name.to_lowercase(), subsection))
+        }
+        None => valid_name(header).then(|| header.to_lowercase()),
+    }
+}
+
+fn format_section(section: &str) -> String {
+    match section.split_once('.') {
+        Some((name, subsection)) => format!("[{} \"{}\"]", name, subsection),
+        None => format!("[{}]", section),
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+// Lowercase the section and key of "section[.subsection].key", leaving the subsection as is
+pub fn normalize_key(key: &str) -> std::io::Result<String> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid config key '{}', expected section.key", key),
+        )
+    };
+    let (section, rest) = key.split_once('.').ok_or_else(invalid)?;
+    let (subsection, name) = match rest.rsplit_once('.') {
+        Some((subsection, name)) => (Some(subsection), name),
+        None => (None, rest),
+    };
+    if !valid_name(section) || !valid_name(name) || subsection.is_some_and(str::is_empty) {
+        return Err(invalid());
+    }
+    Ok(match subsection {
+        Some(subsection) => format!("{}.{}.{}", section.to_lowercase(), subsection, name.to_lowercase()),
+        None => format!("{}.{}", section.to_lowercase(), name.to_lowercase()),
+    })
+}
+
+fn quote(value: &str) -> String {
+    let needs_quotes = value.trim() != value || value.contains(['"', '\\', '#', ';']);
+    if !needs_quotes {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Value as written after '=': double quotes group text and are dropped, a
+// backslash escapes the next character and '#' or ';' outside quotes starts a
+// comment. Unquoted whitespace at the end is not part of the value.
+fn unquote(value: &str) -> String {
+    let mut result = String::new();
+    // Length of `result` without trailing unquoted whitespace
+    let mut kept = 0;
+    let mut quoted = false;
+    let mut chars = value.trim_start().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' => {
+                result.extend(chars.next());
+                kept = result.len();
+            }
+            '#' | ';' if !quoted => break,
+            c => {
+                result.push(c);
+                if quoted || !c.is_whitespace() {
+                    kept = result.len();
+                }
+            }
+        }
+    }
+    result.truncate(kept);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(content: &str) -> ConfigFile {
+        ConfigFile {
+            path: PathBuf::from("config"),
+            lines: content.lines().map(String::from).collect(),
+        }
+    }
+
+    #[test]
+    fn parses_sections_and_subsections() {
+        let config = file("[user]\n\tname = Jane Doe\n[Remote \"Origin\"]\n\tURL = ../shared\n[core]\nbare=false");
+        assert_eq!(config.get("user.name").as_deref(), Some("Jane Doe"));
+        assert_eq!(config.get("USER.Name").as_deref(), Some("Jane Doe"));
+        // Section and key names ignore case, subsections don't
+        assert_eq!(config.get("remote.Origin.url").as_deref(), Some("../shared"));
+        assert_eq!(config.get("remote.origin.url"), None);
+        assert_eq!(config.get("core.bare").as_deref(), Some("false"));
+        assert_eq!(
+            config.list().unwrap(),
+            [
+                ("user.name".to_string(), "Jane Doe".to_string()),
+                ("remote.Origin.url".to_string(), "../shared".to_string()),
+                ("core.bare".to_string(), "false".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for content in ["name = x", "[user\nname = x", "[remote origin]", "[user]\nname", "[user]\nbad name = x", "[]"] {
+            assert!(file(content).entries().is_err(), "{:?}", content);
+        }
+    }
+
+    #[test]
+    fn quoting_and_escapes() {
+        let config = file(concat!(
+            "[test]\n",
+            "padded = \"  two spaces  \"\n",
+            "escaped = \"say \\\"hi\\\" \\\\ done\"\n",
+            "mixed = a \"b c\" d\n",
+            "empty = \"\"\n",
+            "bare =\n",
+        ));
+        assert_eq!(config.get("test.padded").as_deref(), Some("  two spaces  "));
+        assert_eq!(config.get("test.escaped").as_deref(), Some("say \"hi\" \\ done"));
+        assert_eq!(config.get("test.mixed").as_deref(), Some("a b c d"));
+        assert_eq!(config.get("test.empty").as_deref(), Some(""));
+        assert_eq!(config.get("test.bare").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn strips_comments_outside_quotes() {
+        let config = file(concat!(
+            "# leading comment\n",
+            "; another\n",
+            "[user]\n",
+            "\tname = Jane   # trailing comment\n",
+            "\temail = jane@example.com;work\n",
+            "\tsignature = \"# not; a comment\" # but this is\n",
+            "\tescaped = a \\# b\n",
+        ));
+        assert_eq!(config.get("user.name").as_deref(), Some("Jane"));
+        assert_eq!(config.get("user.email").as_deref(), Some("jane@example.com"));
+        assert_eq!(config.get("user.signature").as_deref(), Some("# not; a comment"));
+        assert_eq!(config.get("user.escaped").as_deref(), Some("a # b"));
+    }
+
+    #[test]
+    fn set_quotes_values_that_need_it() {
+        let mut config = file("# keep me\n[user]\n\tname = Old\n");
+        for value in ["plain", " padded ", "with # hash", "semi;colon", "quote \" and \\ slash", ""] {
+            config.set("user.name", value).unwrap();
+            assert_eq!(config.get("user.name").as_deref(), Some(value));
+        }
+        config.set("remote.Origin.url", "../x").unwrap();
+        assert_eq!(config.lines[0], "# keep me");
+        assert_eq!(config.lines[3], "[remote \"Origin\"]");
+        assert!(config.unset("user.name").unwrap());
+        assert_eq!(config.get("user.name"), None);
+        assert!(!config.unset("user.name").unwrap());
+    }
+
+    #[test]
+    fn later_settings_and_layers_take_precedence() {
+        let config = Config {
+            files: vec![
+                (Scope::System, file("[user]\nname = System\nemail = system@example.com\n[gc]\ngraceDays = 3")),
+                (Scope::Global, file("[user]\nname = Global\n[user]\nname = Global again")),
+                (Scope::Local, file("[user]\nname = Local")),
+            ],
+        };
+        assert_eq!(config.get("user.name").as_deref(), Some("Local"));
+        assert_eq!(config.get("user.email").as_deref(), Some("system@example.com"));
+        assert_eq!(config.gc_grace_days().unwrap(), 3);
+        assert_eq!(config.files[1].1.get("user.name").as_deref(), Some("Global again"));
+        assert_eq!(config.list().unwrap().len(), 6);
+
+        let broken = Config { files: vec![(Scope::Local, file("[diff]\ncontext = -1"))] };
+        assert!(broken.diff_context().is_err());
+    }
+}
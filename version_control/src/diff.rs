@@ -0,0 +1,402 @@
+use std::ops::{Index, IndexMut, Range};
+
+// Context lines shown around each change in a unified diff
+pub const DEFAULT_CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffOp {
+    Equal,
+    Delete,
+    Insert,
+}
+
+// A run of lines sharing one operation. For deletions `new` is the empty
+// position the lines were removed at, for insertions `old` is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffHunk {
+    pub op: DiffOp,
+    pub old: Range<usize>,
+    pub new: Range<usize>,
+}
+
+// Shortest edit script between `old` and `new` (Myers' algorithm, linear space
+// variant) as a list of equal/delete/insert hunks in order.
+pub fn diff_lines<T: PartialEq>(old: &[T], new: &[T]) -> Vec<DiffHunk> {
+    let max_d = (old.len() + new.len()).div_ceil(2) + 1;
+    let mut forward = DiagonalVec::new(max_d);
+    let mut backward = DiagonalVec::new(max_d);
+    let mut hunks = Vec::new();
+
+    conquer(old, 0..old.len(), new, 0..new.len(), &mut forward, &mut backward, &mut hunks);
+    hunks
+}
+
+// Split the problem at the middle snake and solve both halves recursively
+fn conquer<T: PartialEq>(
+    old: &[T],
+    mut old_range: Range<usize>,
+    new: &[T],
+    mut new_range: Range<usize>,
+    forward: &mut DiagonalVec,
+    backward: &mut DiagonalVec,
+    hunks: &mut Vec<DiffHunk>,
+) {
+    let prefix = common_prefix(&old[old_range.clone()], &new[new_range.clone()]);
+    push_hunk(hunks, DiffOp::Equal, old_range.start..old_range.start + prefix, new_range.start..new_range.start + prefix);
+    old_range.start += prefix;
+    new_range.start += prefix;
+
+    let suffix = common_suffix(&old[old_range.clone()], &new[new_range.clone()]);
+    let suffix_old = old_range.end - suffix..old_range.end;
+    let suffix_new = new_range.end - suffix..new_range.end;
+    old_range.end -= suffix;
+    new_range.end -= suffix;
+
+    if old_range.is_empty() || new_range.is_empty() {
+        push_hunk(hunks, DiffOp::Delete, old_range.clone(), new_range.start..new_range.start);
+        push_hunk(hunks, DiffOp::Insert, old_range.end..old_range.end, new_range);
+    } else if let Some((x, y)) = middle_snake(old, old_range.clone(), new, new_range.clone(), forward, backward) {
+        conquer(old, old_range.start..x, new, new_range.start..y, forward, backward, hunks);
+        conquer(old, x..old_range.end, new, y..new_range.end, forward, backward, hunks);
+    } else {
+        push_hunk(hunks, DiffOp::Delete, old_range.clone(), new_range.start..new_range.start);
+        push_hunk(hunks, DiffOp::Insert, old_range.end..old_range.end, new_range);
+    }
+
+    push_hunk(hunks, DiffOp::Equal, suffix_old, suffix_new);
+}
+
+// Find where the forward and backward searches overlap; returns the start of
+// the snake in absolute (old, new) coordinates
+fn middle_snake<T: PartialEq>(
+    old: &[T],
+    old_range: Range<usize>,
+    new: &[T],
+    new_range: Range<usize>,
+    forward: &mut DiagonalVec,
+    backward: &mut DiagonalVec,
+) -> Option<(usize, usize)> {
+    let n = old_range.len();
+    let m = new_range.len();
+    let delta = n as isize - m as isize;
+    let odd = delta & 1 == 1;
+    forward[1] = 0;
+    backward[1] = 0;
+
+    let max_d = (n + m).div_ceil(2) + 1;
+    for d in 0..max_d as isize {
+        for k in (-d..=d).rev().step_by(2) {
+            let mut x = if k == -d || (k != d && forward[k - 1] < forward[k + 1]) {
+                forward[k + 1]
+            } else {
+                forward[k - 1] + 1
+            };
+            let y = (x as isize - k) as usize;
+            let (x0, y0) = (x, y);
+            if x < n && y < m {
+                x += common_prefix(
+                    &old[old_range.start + x..old_range.end],
+                    &new[new_range.start + y..new_range.end],
+                );
+            }
+            forward[k] = x;
+            if odd && (k - delta).abs() < d && forward[k] + backward[-(k - delta)] >= n {
+                return Some((old_range.start + x0, new_range.start + y0));
+            }
+        }
+
+        for k in (-d..=d).rev().step_by(2) {
+            let mut x = if k == -d || (k != d && backward[k - 1] < backward[k + 1]) {
+                backward[k + 1]
+            } else {
+                backward[k - 1] + 1
+            };
+            let mut y = (x as isize - k) as usize;
+            if x < n && y < m {
+                let advance = common_suffix(
+                    &old[old_range.start..old_range.start + n - x],
+                    &new[new_range.start..new_range.start + m - y],
+                );
+                x += advance;
+                y += advance;
+            }
+            backward[k] = x;
+            if !odd && (k - delta).abs() <= d && backward[k] + forward[-(k - delta)] >= n {
+                return Some((old_range.start + n - x, new_range.start + m - y));
+            }
+        }
+    }
+    None
+}
+
+// Append a hunk, extending the previous one when the operation repeats
+fn push_hunk(hunks: &mut Vec<DiffHunk>, op: DiffOp, old: Range<usize>, new: Range<usize>) {
+    if old.is_empty() && new.is_empty() {
+        return;
+    }
+
+    // Keep deletions ahead of insertions so replaced lines read naturally
+    if op == DiffOp::Delete && hunks.last().is_some_and(|last| last.op == DiffOp::Insert) {
+        let insert = hunks.pop().unwrap();
+        push_hunk(hunks, DiffOp::Delete, old.clone(), insert.new.start..insert.new.start);
+        push_hunk(hunks, DiffOp::Insert, old.end..old.end, insert.new);
+        return;
+    }
+
+    if let Some(last) = hunks.last_mut() {
+        if last.op == op && last.old.end == old.start && last.new.end == new.start {
+            last.old.end = old.end;
+            last.new.end = new.end;
+            return;
+        }
+    }
+    hunks.push(DiffHunk { op, old, new });
+}
+
+fn common_prefix<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+fn common_suffix<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    a.iter().rev().zip(b.iter().rev()).take_while(|(x, y)| x == y).count()
+}
+
+// Furthest reaching x per diagonal k, indexable by negative diagonals
+struct DiagonalVec {
+    offset: isize,
+    values: Vec<usize>,
+}
+
+impl DiagonalVec {
+    fn new(max_d: usize) -> Self {
+        DiagonalVec {
+            offset: max_d as isize,
+            values: vec![0; 2 * max_d + 1],
+        }
+    }
+}
+
+impl Index<isize> for DiagonalVec {
+    type Output = usize;
+
+    fn index(&self, k: isize) -> &usize {
+        &self.values[(k + self.offset) as usize]
+    }
+}
+
+impl IndexMut<isize> for DiagonalVec {
+    fn index_mut(&mut self, k: isize) -> &mut usize {
+        &mut self.values[(k + self.offset) as usize]
+    }
+}
+
+// Render a unified diff between two texts, or an empty string when they are
+// identical. `None` stands for a file that doesn't exist on that side.
+pub fn unified_diff(
+    old: Option<&str>,
+    new: Option<&str>,
+    path: &str,
+    context: usize,
+) -> String {
+    let old_lines: Vec<&str> = old.unwrap_or("").split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.unwrap_or("").split_inclusive('\n').collect();
+    let hunks = diff_lines(&old_lines, &new_lines);
+    if old.is_some() && new.is_some() && hunks.iter().all(|h| h.op == DiffOp::Equal) {
+        return String::new();
+    }
+
+    let mut output = format!("diff --git a/{} b/{}\n", path, path);
+    match (old, new) {
+        (None, _) => {
+            output.push_str("new file mode 100644\n");
+            output.push_str(&format!("--- /dev/null\n+++ b/{}\n", path));
+        }
+        (_, None) => {
+            output.push_str("deleted file mode 100644\n");
+            output.push_str(&format!("--- a/{}\n+++ /dev/null\n", path));
+        }
+        _ => output.push_str(&format!("--- a/{}\n+++ b/{}\n", path, path)),
+    }
+
+    // One entry per line: operation plus its position in the old and new text
+    let mut lines = Vec::new();
+    for hunk in &hunks {
+        match hunk.op {
+            DiffOp::Equal => lines.extend(hunk.old.clone().zip(hunk.new.clone()).map(|(o, n)| (DiffOp::Equal, o, n))),
+            DiffOp::Delete => lines.extend(hunk.old.clone().map(|o| (DiffOp::Delete, o, hunk.new.start))),
+            DiffOp::Insert => lines.extend(hunk.new.clone().map(|n| (DiffOp::Insert, hunk.old.start, n))),
+        }
+    }
+
+    let changes: Vec<usize> = (0..lines.len()).filter(|&i| lines[i].0 != DiffOp::Equal).collect();
+    let mut group_start = 0;
+    while group_start < changes.len() {
+        // Changes separated by at most 2 * context unchanged lines share a hunk
+        let mut group_end = group_start;
+        while group_end + 1 < changes.len() && changes[group_end + 1] - changes[group_end] <= 2 * context + 1 {
+            group_end += 1;
+        }
+
+        let first = changes[group_start].saturating_sub(context);
+        let last = (changes[group_end] + context + 1).min(lines.len());
+        let hunk_lines = &lines[first..last];
+
+        let old_count = hunk_lines.iter().filter(|l| l.0 != DiffOp::Insert).count();
+        let new_count = hunk_lines.iter().filter(|l| l.0 != DiffOp::Delete).count();
+        output.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(hunk_lines[0].1, old_count),
+            hunk_range(hunk_lines[0].2, new_count)
+        ));
+
+        for &(op, old_index, new_index) in hunk_lines {
+            let (prefix, line) = match op {
+                DiffOp::Equal => (' ', old_lines[old_index]),
+                DiffOp::Delete => ('-', old_lines[old_index]),
+                DiffOp::Insert => ('+', new_lines[new_index]),
+            };
+            output.push(prefix);
+            output.push_str(line);
+            if !line.ends_with('\n') {
+                output.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+
+        group_start = group_end + 1;
+    }
+
+    output
+}
+
+// "start,count" for a hunk header; empty ranges point at the preceding line
+// and single line ranges omit the count
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, count),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift64, enough to shake out edge cases without pulling in a crate
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+
+        // Short sequences over a small alphabet, so matches are common
+        fn sequence(&mut self) -> Vec<u8> {
+            let len = self.below(40);
+            let alphabet = self.below(6) + 1;
+            (0..len).map(|_| self.below(alphabet) as u8).collect()
+        }
+    }
+
+    fn lcs_len(a: &[u8], b: &[u8]) -> usize {
+        let mut table = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in 0..a.len() {
+            for j in 0..b.len() {
+                table[i + 1][j + 1] = if a[i] == b[j] {
+                    table[i][j] + 1
+                } else {
+                    table[i][j + 1].max(table[i + 1][j])
+                };
+            }
+        }
+        table[a.len()][b.len()]
+    }
+
+    // Hunks must walk both sequences end to end without gaps, and equal hunks
+    // must really be equal; returns the number of lines kept
+    fn check_script(old: &[u8], new: &[u8], hunks: &[DiffHunk]) -> usize {
+        let (mut o, mut n, mut kept) = (0, 0, 0);
+        for hunk in hunks {
+            assert_eq!((hunk.old.start, hunk.new.start), (o, n), "gap before {:?}", hunk);
+            match hunk.op {
+                DiffOp::Equal => {
+                    assert_eq!(hunk.old.len(), hunk.new.len());
+                    assert_eq!(old[hunk.old.clone()], new[hunk.new.clone()]);
+                    kept += hunk.old.len();
+                }
+                DiffOp::Delete => assert!(hunk.new.is_empty() && !hunk.old.is_empty()),
+                DiffOp::Insert => assert!(hunk.old.is_empty() && !hunk.new.is_empty()),
+            }
+            o = hunk.old.end;
+            n = hunk.new.end;
+        }
+        assert_eq!((o, n), (old.len(), new.len()));
+        kept
+    }
+
+    #[test]
+    fn diff_lines_is_minimal_on_random_input() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..2000 {
+            let old = rng.sequence();
+            let new = rng.sequence();
+            let hunks = diff_lines(&old, &new);
+            assert_eq!(check_script(&old, &new, &hunks), lcs_len(&old, &new), "{:?} -> {:?}", old, new);
+        }
+    }
+
+    #[test]
+    fn diff_lines_edge_cases() {
+        assert!(diff_lines::<u8>(&[], &[]).is_empty());
+        assert_eq!(
+            diff_lines(&[1, 2], &[]),
+            vec![DiffHunk { op: DiffOp::Delete, old: 0..2, new: 0..0 }]
+        );
+        assert_eq!(
+            diff_lines(&[], &[1, 2]),
+            vec![DiffHunk { op: DiffOp::Insert, old: 0..0, new: 0..2 }]
+        );
+        assert_eq!(
+            diff_lines(&[1, 2, 3], &[1, 2, 3]),
+            vec![DiffHunk { op: DiffOp::Equal, old: 0..3, new: 0..3 }]
+        );
+    }
+
+    #[test]
+    fn replaced_lines_delete_before_insert() {
+        let hunks = diff_lines(&["a", "b", "c"], &["a", "x", "c"]);
+        let ops: Vec<DiffOp> = hunks.iter().map(|h| h.op).collect();
+        assert_eq!(ops, [DiffOp::Equal, DiffOp::Delete, DiffOp::Insert, DiffOp::Equal]);
+    }
+
+    #[test]
+    fn unified_diff_format() {
+        assert_eq!(unified_diff(Some("a\n"), Some("a\n"), "f", DEFAULT_CONTEXT), "");
+        assert_eq!(
+            unified_diff(Some("a\nb\nc\n"), Some("a\nx\nc"), "f", DEFAULT_CONTEXT),
+            "diff --git a/f b/f\n--- a/f\n+++ b/f\n@@ -1,3 +1,3 @@\n a\n-b\n-c\n+x\n+c\n\\ No newline at end of file\n"
+        );
+        assert_eq!(
+            unified_diff(None, Some("new\n"), "f", DEFAULT_CONTEXT),
+            "diff --git a/f b/f\nnew file mode 100644\n--- /dev/null\n+++ b/f\n@@ -0,0 +1 @@\n+new\n"
+        );
+    }
+
+    #[test]
+    fn unified_diff_splits_distant_changes() {
+        let old: String = (0..20).map(|i| format!("{}\n", i)).collect();
+        let new: String = (0..20)
+            .map(|i| match i {
+                2 => "two\n".to_string(),
+                17 => "seventeen\n".to_string(),
+                _ => format!("{}\n", i),
+            })
+            .collect();
+        let diff = unified_diff(Some(&old), Some(&new), "f", DEFAULT_CONTEXT);
+        let headers: Vec<&str> = diff.lines().filter(|l| l.starts_with("@@")).collect();
+        assert_eq!(headers, ["@@ -1,6 +1,6 @@", "@@ -15,6 +15,6 @@"]);
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::backup;
+use crate::repository::Repository;
+use crate::utils;
+
+// Unreachable objects younger than this are kept, in case whatever wrote them
+// is about to refer to them
+pub const DEFAULT_GRACE_DAYS: usize = 14;
+
+// A file in the object store that can be deleted
+#[derive(Debug)]
+pub struct Garbage {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+#[derive(Debug)]
+pub struct Sweep {
+    pub garbage: Vec<Garbage>,
+    // Unreachable objects still inside the grace period
+    pub recent: usize,
+}
+
+// What fsck found in the object store
+#[derive(Debug)]
+pub struct ObjectCheck {
+    pub stored: usize,
+    // Objects whose content no longer hashes to their name, with what it hashes to
+    pub corrupt: BTreeMap<String, String>,
+    // Referenced objects that aren't stored at all
+    pub missing: BTreeSet<String>,
+    // Stored objects nothing refers to
+    pub dangling: BTreeSet<String>,
+}
+
+// Where each object is used, as (what refers to it, path): a commit reachable
+// from a ref, the staging area or a backup. Commits nothing leads to any more,
+// such as those a rebase replaced or a deleted branch held, don't count.
+// There is no stash; backups are the closest thing and keep their snapshots
+// alive until they are pruned.
+pub fn references(repo: &Repository) -> std::io::Result<BTreeMap<String, Vec<(String, String)>>> {
+    let tips: Vec<String> = repo.refs()?.into_iter().filter_map(|(_, id)| id).collect();
+    let live = repo.reachable_commits(tips.iter().map(String::as_str));
+
+    let mut references: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+    for commit in repo.commits.iter().filter(|c| live.contains(&c.id)) {
+        let what = format!("commit {}", &commit.id[..8.min(commit.id.len())]);
+        for (path, hash) in &commit.files {
+            references.entry(hash.clone()).or_default().push((what.clone(), path.clone()));
+        }
+    }
+    for (path, hash) in &repo.staging {
+        references.entry(hash.clone()).or_default().push(("staging area".to_string(), path.clone()));
+    }
+    for backup in backup::list(repo)? {
+        for (path, hash) in backup.files {
+            references.entry(hash).or_default().push((format!("backup {}", backup.id), path));
+        }
+    }
+    Ok(references)
+}
+
+// Objects gc keeps regardless of age; fsck calls everything else dangling
+pub fn reachable(repo: &Repository) -> std::io::Result<HashSet<String>> {
+    Ok(references(repo)?.into_keys().collect())
+}
+
+// Rehash every stored object and compare what is stored with `references`
+pub fn check_objects(
+    repo: &Repository,
+    references: &BTreeMap<String, Vec<(String, String)>>,
+) -> std::io::Result<ObjectCheck> {
+    let stored = repo.list_objects()?;
+    let mut corrupt = BTreeMap::new();
+    for hash in &stored {
+        let actual = utils::hash_file(&repo.object_path(hash)?)?;
+        if actual != *hash {
+            corrupt.insert(hash.clone(), actual);
+        }
+    }
+    let stored: HashSet<String> = stored.into_iter().collect();
+    Ok(ObjectCheck {
+        stored: stored.len(),
+        corrupt,
+        missing: references.keys().filter(|hash| !stored.contains(*hash)).cloned().collect(),
+        dangling: stored.into_iter().filter(|hash| !references.contains_key(hash)).collect(),
+    })
+}
+
+// Unreachable objects, and temporary files left by interrupted writes, that
+// were last modified more than `grace` ago
+pub fn find_garbage(repo: &Repository, grace: Duration) -> std::io::Result<Sweep> {
+    let reachable = reachable(repo)?;
+    let cutoff = SystemTime::now().checked_sub(grace).unwrap_or(SystemTime::UNIX_EPOCH);
+    let mut sweep = Sweep { garbage: Vec::new(), recent: 0 };
+
+    let mut candidates = Vec::new();
+    for hash in repo.list_objects()? {
+        if !reachable.contains(&hash) {
+            candidates.push(repo.object_path(&hash)?);
+        }
+    }
+    let objects_dir = repo.objects_dir();
+    if objects_dir.exists() {
+        for entry in fs::read_dir(&objects_dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() && entry.file_name().to_string_lossy().starts_with("tmp-") {
+                candidates.push(entry.path());
+            }
+        }
+    }
+
+    for path in candidates {
+        let metadata = fs::metadata(&path)?;
+        if metadata.modified()? > cutoff {
+            sweep.recent += 1;
+        } else {
+            sweep.garbage.push(Garbage { path, size: metadata.len() });
+        }
+    }
+    Ok(sweep)
+}
+
+// Delete `garbage`, along with object directories it leaves empty
+pub fn remove(repo: &Repository, garbage: &[Garbage]) -> std::io::Result<()> {
+    let objects_dir = repo.objects_dir();
+    for item in garbage {
+        fs::remove_file(&item.path)?;
+        if let Some(parent) = item.path.parent().filter(|parent| *parent != objects_dir) {
+            // Fails harmlessly while other objects share the directory
+            let _ = fs::remove_dir(parent);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::{Commit, Head};
+    use crate::utils::TempDir;
+    use chrono::Utc;
+    use std::collections::HashMap;
+
+    fn store(repo: &Repository, content: &str) -> String {
+        let hash = utils::calculate_hash_bytes(content.as_bytes());
+        repo.write_object(&hash, content.as_bytes()).unwrap();
+        hash
+    }
+
+    fn add_commit(repo: &mut Repository, parents: &[&str], files: &[(&str, &str)]) -> String {
+        let mut commit = Commit {
+            timestamp: Utc::now(),
+            id: String::new(),
+            parents: parents.iter().map(|p| p.to_string()).collect(),
+            message: String::new(),
+            files: files.iter().map(|(path, hash)| (path.to_string(), hash.to_string())).collect::<HashMap<_, _>>(),
+            author: None,
+            committer: None,
+        };
+        commit.id = commit.compute_id();
+        repo.commits.push(commit.clone());
+        commit.id
+    }
+
+    fn age(repo: &Repository, hash: &str, days: u64) {
+        let file = fs::File::options().write(true).open(repo.object_path(hash).unwrap()).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60)).unwrap();
+    }
+
+    fn is_garbage(repo: &Repository, sweep: &Sweep, hashes: &[&String]) -> bool {
+        let paths: BTreeSet<PathBuf> = sweep.garbage.iter().map(|item| item.path.clone()).collect();
+        paths == hashes.iter().map(|hash| repo.object_path(hash).unwrap()).collect()
+    }
+
+    // A branch with two commits, a commit no ref leads to, a staged file and
+    // an object nothing mentions
+    struct Fixture {
+        _dir: TempDir,
+        repo: Repository,
+        first: String,
+        second: String,
+        staged: String,
+        orphaned: String,
+        stray: String,
+        orphan_commit: String,
+    }
+
+    fn fixture(name: &str) -> Fixture {
+        let dir = TempDir::new(name);
+        let mut repo = Repository::new(dir.0.clone());
+        let first = store(&repo, "first\n");
+        let second = store(&repo, "second\n");
+        let staged = store(&repo, "staged\n");
+        let orphaned = store(&repo, "orphaned\n");
+        let stray = store(&repo, "stray\n");
+
+        let root = add_commit(&mut repo, &[], &[("f", &first)]);
+        let tip = add_commit(&mut repo, &[&root], &[("f", &second)]);
+        let orphan_commit = add_commit(&mut repo, &[&root], &[("f", &orphaned)]);
+        repo.write_branch("master", &tip).unwrap();
+        repo.staging.insert("g".to_string(), staged.clone());
+        Fixture { _dir: dir, repo, first, second, staged, orphaned, stray, orphan_commit }
+    }
+
+    #[test]
+    fn only_refs_staging_and_backups_keep_objects() {
+        let f = fixture("gc-roots");
+        let kept = reachable(&f.repo).unwrap();
+        assert_eq!(kept, HashSet::from([f.first.clone(), f.second.clone(), f.staged.clone()]));
+
+        // Any kind of ref makes the orphaned commit live again
+        f.repo.write_remote_ref("origin", "topic", &f.orphan_commit).unwrap();
+        assert!(reachable(&f.repo).unwrap().contains(&f.orphaned));
+        f.repo.delete_remote_ref("origin", "topic").unwrap();
+
+        f.repo.set_head(&Head::Detached(f.orphan_commit.clone())).unwrap();
+        assert!(reachable(&f.repo).unwrap().contains(&f.orphaned));
+        f.repo.set_head(&Head::Branch("master".to_string())).unwrap();
+
+        let mut repo = f.repo;
+        repo.merge_head = Some(f.orphan_commit.clone());
+        assert!(reachable(&repo).unwrap().contains(&f.orphaned));
+    }
+
+    #[test]
+    fn collects_unreachable_objects_after_the_grace_period() {
+        let f = fixture("gc-grace");
+        let grace = Duration::from_secs(DEFAULT_GRACE_DAYS as u64 * 24 * 60 * 60);
+
+        let sweep = find_garbage(&f.repo, grace).unwrap();
+        assert!(sweep.garbage.is_empty());
+        assert_eq!(sweep.recent, 2);
+
+        age(&f.repo, &f.orphaned, 30);
+        age(&f.repo, &f.first, 30);
+        let sweep = find_garbage(&f.repo, grace).unwrap();
+        assert!(is_garbage(&f.repo, &sweep, &[&f.orphaned]));
+        assert_eq!(sweep.recent, 1);
+
+        let sweep = find_garbage(&f.repo, Duration::ZERO).unwrap();
+        assert!(is_garbage(&f.repo, &sweep, &[&f.orphaned, &f.stray]));
+        remove(&f.repo, &sweep.garbage).unwrap();
+        assert!(!f.repo.has_object(&f.orphaned) && !f.repo.has_object(&f.stray));
+        assert!(!f.repo.object_path(&f.stray).unwrap().parent().unwrap().exists());
+        for hash in [&f.first, &f.second, &f.staged] {
+            assert!(f.repo.has_object(hash));
+        }
+    }
+
+    #[test]
+    fn finds_dangling_corrupt_and_missing_objects() {
+        let f = fixture("gc-check");
+        let references = references(&f.repo).unwrap();
+        assert_eq!(references[&f.second], [(format!("commit {}", &f.repo.commits[1].id[..8]), "f".to_string())]);
+        assert_eq!(references[&f.staged], [("staging area".to_string(), "g".to_string())]);
+
+        let check = check_objects(&f.repo, &references).unwrap();
+        assert_eq!(check.stored, 5);
+        assert!(check.corrupt.is_empty() && check.missing.is_empty());
+        assert_eq!(check.dangling, BTreeSet::from([f.orphaned.clone(), f.stray.clone()]));
+
+        fs::write(f.repo.object_path(&f.first).unwrap(), "tampered\n").unwrap();
+        fs::remove_file(f.repo.object_path(&f.staged).unwrap()).unwrap();
+        let check = check_objects(&f.repo, &references).unwrap();
+        assert_eq!(check.stored, 4);
+        assert_eq!(
+            check.corrupt,
+            BTreeMap::from([(f.first.clone(), utils::calculate_hash_bytes(b"tampered\n"))])
+        );
+        assert_eq!(check.missing, BTreeSet::from([f.staged.clone()]));
+    }
+}
@@ -0,0 +1,299 @@
+use std::fs;
+use std::path::Path;
+
+pub const IGNORE_FILE: &str = ".mini-gitignore";
+
+// One line of .mini-gitignore
+#[derive(Debug)]
+pub struct IgnorePattern {
+    pub line: usize,
+    pub text: String,
+    glob: Vec<char>,
+    negated: bool,
+    dir_only: bool,
+    // Matched against the whole path instead of just the file name
+    anchored: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct IgnoreRules {
+    patterns: Vec<IgnorePattern>,
+}
+
+impl IgnoreRules {
+    pub fn load(working_dir: &Path) -> std::io::Result<Self> {
+        match fs::read_to_string(working_dir.join(IGNORE_FILE)) {
+            Ok(content) => Ok(Self::parse(&content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let patterns = content
+            .lines()
+            .enumerate()
+            .filter_map(|(index, line)| IgnorePattern::parse(line, index + 1))
+            .collect();
+        IgnoreRules { patterns }
+    }
+
+    // `relative_path` uses '/' separators and is relative to the repository root
+    pub fn is_ignored(&self, relative_path: &str, is_dir: bool) -> bool {
+        self.matching_pattern(relative_path, is_dir)
+            .is_some_and(|pattern| !pattern.negated)
+    }
+
+    // The pattern that decides whether the path is ignored, if any. A file
+    // inside an ignored directory stays ignored whatever later patterns say.
+    pub fn matching_pattern(&self, relative_path: &str, is_dir: bool) -> Option<&IgnorePattern> {
+        let components: Vec<&str> = relative_path.split('/').filter(|c| !c.is_empty()).collect();
+        for depth in 1..components.len() {
+            let parent = components[..depth].join("/");
+            if let Some(pattern) = self.last_match(&parent, true).filter(|p| !p.negated) {
+                return Some(pattern);
+            }
+        }
+        self.last_match(&components.join("/"), is_dir)
+    }
+
+    fn last_match(&self, path: &str, is_dir: bool) -> Option<&IgnorePattern> {
+        self.patterns.iter().rev().find(|pattern| pattern.matches(path, is_dir))
+    }
+}
+
+impl IgnorePattern {
+    fn parse(line: &str, line_number: usize) -> Option<Self> {
+        // Trailing spaces are ignored unless escaped with a backslash
+        let mut text = line.trim_end_matches('\r').to_string();
+        while text.ends_with(' ') && !text.ends_with("\\ ") {
+            text.pop();
+        }
+        if text.is_empty() || text.starts_with('#') {
+            return None;
+        }
+
+        // A leading backslash escapes a literal '!' or '#'
+        let negated = text.starts_with('!');
+        let escaped = text.starts_with("\\!") || text.starts_with("\\#");
+        let pattern = if negated || escaped { &text[1..] } else { text.as_str() };
+
+        let dir_only = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        // A slash anywhere but the end ties the pattern to the repository root
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+        if pattern.is_empty() {
+            return None;
+        }
+
+        Some(IgnorePattern {
+            line: line_number,
+            text: line.to_string(),
+            glob: pattern.chars().collect(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let subject: Vec<char> = if self.anchored {
+            path.chars().collect()
+        } else {
+            path.rsplit('/').next().unwrap_or(path).chars().collect()
+        };
+        glob_match(&self.glob, &subject)
+    }
+}
+
+// Gitignore flavoured glob: `*` and `?` stop at '/', `**` spans directories,
+// `[...]` is a character class and backslash escapes the next character
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    let Some(&first) = pattern.first() else {
+        return text.is_empty();
+    };
+
+    match first {
+        '*' if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            if rest.is_empty() {
+                return true;
+            }
+            if rest[0] == '/' {
+                // "**/" matches zero or more leading directories
+                let rest = &rest[1..];
+                if glob_match(rest, text) {
+                    return true;
+                }
+                return text
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| **c == '/')
+                    .any(|(index, _)| glob_match(rest, &text[index + 1..]));
+            }
+            glob_match(&pattern[1..], text)
+        }
+        '*' => {
+            let rest = &pattern[1..];
+            for split in 0..=text.len() {
+                if glob_match(rest, &text[split..]) {
+                    return true;
+                }
+                if split < text.len() && text[split] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        '?' => !text.is_empty() && text[0] != '/' && glob_match(&pattern[1..], &text[1..]),
+        '[' => match (text.first(), match_class(&pattern[1..], text.first().copied())) {
+            (Some(_), Some((true, consumed))) => glob_match(&pattern[1 + consumed..], &text[1..]),
+            (_, None) => !text.is_empty() && text[0] == '[' && glob_match(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        '\\' if pattern.len() > 1 => !text.is_empty() && text[0] == pattern[1] && glob_match(&pattern[2..], &text[1..]),
+        c => !text.is_empty() && text[0] == c && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+// Match `c` against the class body following '['. Returns whether it matched
+// and how many pattern characters the class used, or None if it is unterminated.
+fn match_class(class: &[char], c: Option<char>) -> Option<(bool, usize)> {
+    let negated = matches!(class.first(), Some('!') | Some('^'));
+    let mut index = usize::from(negated);
+    let mut matched = false;
+    let mut first = true;
+
+    while index < class.len() {
+        let start = class[index];
+        if start == ']' && !first {
+            let matched = c.is_some_and(|c| c != '/') && matched != negated;
+            return Some((matched, index + 1));
+        }
+        first = false;
+        if class.get(index + 1) == Some(&'-') && class.get(index + 2).is_some_and(|end| *end != ']') {
+            let end = class[index + 2];
+            matched |= c.is_some_and(|c| start <= c && c <= end);
+            index += 3;
+        } else {
+            matched |= c == Some(start);
+            index += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(rules: &str, cases: &[(&str, bool)]) {
+        let rules = IgnoreRules::parse(rules);
+        for &(path, ignored) in cases {
+            assert_eq!(rules.is_ignored(path, false), ignored, "{:?} against {:?}", path, rules);
+        }
+    }
+
+    #[test]
+    fn unanchored_patterns_match_file_names() {
+        check("*.log\nbuild", &[
+            ("a.log", true),
+            ("deep/dir/a.log", true),
+            ("a.log.txt", false),
+            ("build", true),
+            ("src/build", true),
+            ("builder", false),
+        ]);
+    }
+
+    #[test]
+    fn slashes_anchor_to_the_root() {
+        check("/target\ndocs/*.html", &[
+            ("target", true),
+            ("src/target", false),
+            ("docs/index.html", true),
+            ("docs/api/index.html", false),
+            ("other/docs/index.html", false),
+        ]);
+    }
+
+    #[test]
+    fn double_star_spans_directories() {
+        check("**/cache\na/**/z\nlogs/**", &[
+            ("cache", true),
+            ("x/y/cache", true),
+            ("a/z", true),
+            ("a/b/c/z", true),
+            ("b/a/z", false),
+            ("logs/today", true),
+            ("logs/2024/01/today", true),
+            ("logs", false),
+        ]);
+    }
+
+    #[test]
+    fn wildcards_stop_at_slashes() {
+        check("src/*.rs\nsrc/?.c", &[
+            ("src/main.rs", true),
+            ("src/bin/main.rs", false),
+            ("src/a.c", true),
+            ("src/ab.c", false),
+        ]);
+        assert!(!glob_match(&['a', '?', 'b'], &['a', '/', 'b']));
+    }
+
+    #[test]
+    fn character_classes() {
+        check("file[0-9].txt\n[!a-c]x\n[]]y\n[z", &[
+            ("file3.txt", true),
+            ("filex.txt", false),
+            ("dx", true),
+            ("bx", false),
+            ("]y", true),
+            ("[z", true),
+        ]);
+    }
+
+    #[test]
+    fn directory_only_patterns() {
+        let rules = IgnoreRules::parse("out/");
+        assert!(rules.is_ignored("out", true));
+        assert!(!rules.is_ignored("out", false));
+        assert!(rules.is_ignored("out/file", false));
+        assert!(rules.is_ignored("sub/out/file", false));
+    }
+
+    #[test]
+    fn negation_and_ignored_parents() {
+        check("*.log\n!keep.log", &[("a.log", true), ("keep.log", false), ("dir/keep.log", false)]);
+        // Re-including a file inside an ignored directory has no effect
+        check("vendor/\n!vendor/keep", &[("vendor/keep", true)]);
+        check("vendor/*\n!vendor/keep", &[("vendor/keep", false), ("vendor/other", true)]);
+    }
+
+    #[test]
+    fn comments_escapes_and_whitespace() {
+        check("# comment\n\\#hash\n\\!bang\ntrailing   \nspace\\ ", &[
+            ("# comment", false),
+            ("#hash", true),
+            ("!bang", true),
+            ("trailing", true),
+            ("space ", true),
+            ("space", false),
+        ]);
+        assert!(IgnoreRules::parse("\n   \n# only comments\n/\n").patterns.is_empty());
+    }
+
+    #[test]
+    fn reports_the_deciding_line() {
+        let rules = IgnoreRules::parse("*.tmp\n\n!a.tmp\nlogs/");
+        assert_eq!(rules.matching_pattern("a.tmp", false).map(|p| p.line), Some(3));
+        assert_eq!(rules.matching_pattern("b.tmp", false).map(|p| p.line), Some(1));
+        assert_eq!(rules.matching_pattern("logs/x.tmp", false).map(|p| p.line), Some(4));
+        assert!(rules.matching_pattern("readme", false).is_none());
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+
+use crate::repository::Repository;
+use crate::utils;
+
+// Stat cache for the working tree, kept in .mini-git/index so unchanged files
+// don't have to be read and hashed again. Layout, integers big-endian:
+//
+//     "MGIX" | version u32 | entry count u32
+//     per entry: mtime i64 + u32 nanos | ctime i64 + u32 nanos | size u64
+//                | inode u64 | SHA-256 of the content, 32 bytes
+//                | path length u16 | path bytes
+//     SHA-256 of everything above
+//
+// It only speeds things up: a missing or damaged file just means everything
+// gets hashed again.
+
+const MAGIC: &[u8; 4] = b"MGIX";
+const INDEX_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Timestamp {
+    secs: i64,
+    nanos: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+    mtime: Timestamp,
+    ctime: Timestamp,
+    size: u64,
+    inode: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    stat: Stat,
+    hash: String,
+}
+
+#[derive(Debug)]
+pub struct Index {
+    path: PathBuf,
+    working_dir: PathBuf,
+    // When the index file was last written; see `is_racy`
+    written: Option<Timestamp>,
+    entries: HashMap<String, Entry>,
+    changed: bool,
+}
+
+impl Index {
+    pub fn load(repo: &Repository) -> std::io::Result<Self> {
+        let path = repo.repo_dir().join("index");
+        let mut index = Index {
+            path,
+            working_dir: repo.working_dir.clone(),
+            written: None,
+            entries: HashMap::new(),
+            changed: false,
+        };
+
+        let (data, metadata) = match fs::read(&index.path).and_then(|data| Ok((data, fs::metadata(&index.path)?))) {
+            Ok(found) => found,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(index),
+            Err(e) => return Err(e),
+        };
+        if let Some(entries) = parse(&data) {
+            index.entries = entries;
+            index.written = Some(stat(&metadata).mtime);
+        }
+        Ok(index)
+    }
+
+    // Hash of a working tree file, read from disk only when its stat data
+    // no longer matches the cache
+    pub fn hash(&mut self, relative_path: &str) -> std::io::Result<String> {
+        let (current, cached) = self.lookup(relative_path)?;
+        if let Some(hash) = cached {
+            return Ok(hash);
+        }
+        let hash = utils::hash_file(&self.working_dir.join(relative_path))?;
+        self.record(relative_path, current, &hash);
+        Ok(hash)
+    }
+
+    // Current stat data of a working tree file, with the cached hash if it
+    // can still be trusted. Stat before reading, so a file changing while it
+    // is hashed is caught the next time.
+    pub fn lookup(&self, relative_path: &str) -> std::io::Result<(Stat, Option<String>)> {
+        let current = stat(&fs::metadata(self.working_dir.join(relative_path))?);
+        let cached = self
+            .entries
+            .get(relative_path)
+            .filter(|entry| entry.stat == current && !self.is_racy(&entry.stat))
+            .map(|entry| entry.hash.clone());
+        Ok((current, cached))
+    }
+
+    // Cache the hash of a file read after `lookup` returned `stat`
+    pub fn record(&mut self, relative_path: &str, stat: Stat, hash: &str) {
+        self.entries.insert(relative_path.to_string(), Entry { stat, hash: hash.to_string() });
+        // Written even if nothing changed, so a racily clean entry ends up
+        // older than the index and can be trusted next time
+        self.changed = true;
+    }
+
+    // A file modified in the same clock tick the index was written could
+    // change again afterwards without its stat data changing, so entries not
+    // strictly older than the index file are always rehashed
+    fn is_racy(&self, stat: &Stat) -> bool {
+        self.written.is_none_or(|written| stat.mtime >= written)
+    }
+
+    // Write the index back if anything was hashed, keeping only paths the next
+    // commit would record
+    pub fn save(&mut self, repo: &Repository) -> std::io::Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        let tracked = repo.staged_tree();
+        self.entries.retain(|path, _| tracked.contains_key(path));
+
+        utils::write_file_atomic(&self.path, &encode(&self.entries)?)?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+fn encode(entries: &HashMap<String, Entry>) -> std::io::Result<Vec<u8>> {
+    let mut paths: Vec<&String> = entries.keys().collect();
+    paths.sort();
+
+    let mut data = Vec::new();
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&INDEX_VERSION.to_be_bytes());
+    data.extend_from_slice(&(paths.len() as u32).to_be_bytes());
+    for path in paths {
+        let entry = &entries[path];
+        for time in [entry.stat.mtime, entry.stat.ctime] {
+            data.extend_from_slice(&time.secs.to_be_bytes());
+            data.extend_from_slice(&time.nanos.to_be_bytes());
+        }
+        data.extend_from_slice(&entry.stat.size.to_be_bytes());
+        data.extend_from_slice(&entry.stat.inode.to_be_bytes());
+        data.extend_from_slice(&hex_decode(&entry.hash).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid hash for {}: {}", path, entry.hash),
+            )
+        })?);
+        data.extend_from_slice(&(path.len() as u16).to_be_bytes());
+        data.extend_from_slice(path.as_bytes());
+    }
+    let checksum = Sha256::digest(&data);
+    data.extend_from_slice(&checksum);
+    Ok(data)
+}
+
+fn parse(data: &[u8]) -> Option<HashMap<String, Entry>> {
+    let (body, checksum) = data.split_at_checked(data.len().checked_sub(32)?)?;
+    if Sha256::digest(body).as_slice() != checksum {
+        return None;
+    }
+
+    let mut reader = Reader { data: body };
+    if reader.take(4)? != MAGIC || reader.u32()? != INDEX_VERSION {
+        return None;
+    }
+    let count = reader.u32()?;
+    let mut entries = HashMap::new();
+    for _ in 0..count {
+        let mtime = Timestamp { secs: reader.u64()? as i64, nanos: reader.u32()? };
+        let ctime = Timestamp { secs: reader.u64()? as i64, nanos: reader.u32()? };
+        let size = reader.u64()?;
+        let inode = reader.u64()?;
+        let hash = hex_encode(reader.take(32)?);
+        let path_len = u16::from_be_bytes(reader.take(2)?.try_into().ok()?) as usize;
+        let path = String::from_utf8(reader.take(path_len)?.to_vec()).ok()?;
+        entries.insert(path, Entry { stat: Stat { mtime, ctime, size, inode }, hash });
+    }
+    reader.data.is_empty().then_some(entries)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (bytes, rest) = self.data.split_at_checked(len)?;
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
+
+#[cfg(unix)]
+fn stat(metadata: &fs::Metadata) -> Stat {
+    use std::os::unix::fs::MetadataExt;
+    Stat {
+        mtime: Timestamp { secs: metadata.mtime(), nanos: metadata.mtime_nsec() as u32 },
+        ctime: Timestamp { secs: metadata.ctime(), nanos: metadata.ctime_nsec() as u32 },
+        size: metadata.size(),
+        inode: metadata.ino(),
+    }
+}
+
+// Without ctime and inode numbers the modification time and size have to do
+#[cfg(not(unix))]
+fn stat(metadata: &fs::Metadata) -> Stat {
+    use std::time::UNIX_EPOCH;
+    let since_epoch = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    let mtime = Timestamp { secs: since_epoch.as_secs() as i64, nanos: since_epoch.subsec_nanos() };
+    Stat { mtime, ctime: mtime, size: metadata.len(), inode: 0 }
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(secs: i64, hash_byte: u8) -> Entry {
+        let time = Timestamp { secs, nanos: 999_999_999 };
+        Entry {
+            stat: Stat { mtime: time, ctime: Timestamp { secs: secs + 1, nanos: 0 }, size: u64::MAX, inode: 42 },
+            hash: format!("{:02x}", hash_byte).repeat(32),
+        }
+    }
+
+    fn sample() -> HashMap<String, Entry> {
+        HashMap::from([
+            ("a.txt".to_string(), entry(1_700_000_000, 0x00)),
+            ("dir/nested/b.rs".to_string(), entry(-5, 0xab)),
+            ("ünïcode name".to_string(), entry(0, 0xff)),
+        ])
+    }
+
+    // Recompute the trailer after editing the body
+    fn reseal(data: &mut Vec<u8>) {
+        data.truncate(data.len() - 32);
+        let checksum = Sha256::digest(&data);
+        data.extend_from_slice(&checksum);
+    }
+
+    #[test]
+    fn round_trips_entries() {
+        let entries = sample();
+        let parsed = parse(&encode(&entries).unwrap()).unwrap();
+        assert_eq!(parsed.len(), entries.len());
+        for (path, entry) in &entries {
+            assert_eq!(parsed[path].stat, entry.stat);
+            assert_eq!(parsed[path].hash, entry.hash);
+        }
+        assert!(parse(&encode(&HashMap::new()).unwrap()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn layout_is_stable() {
+        let data = encode(&HashMap::from([("p".to_string(), entry(1, 0x11))])).unwrap();
+        assert_eq!(&data[..12], b"MGIX\0\0\0\x01\0\0\0\x01");
+        // mtime, ctime, size, inode, hash, path length, path, checksum
+        assert_eq!(data.len(), 12 + 12 + 12 + 8 + 8 + 32 + 2 + 1 + 32);
+        assert_eq!(&data[12..20], &1i64.to_be_bytes());
+        assert_eq!(&data[data.len() - 35..data.len() - 32], b"\0\x01p");
+    }
+
+    #[test]
+    fn rejects_damaged_data() {
+        let data = encode(&sample()).unwrap();
+        for index in [0, 5, 20, data.len() / 2, data.len() - 1] {
+            let mut damaged = data.clone();
+            damaged[index] ^= 0x01;
+            assert!(parse(&damaged).is_none(), "flipped byte {}", index);
+        }
+        for len in [0, 31, 32, 33, data.len() - 1] {
+            assert!(parse(&data[..len]).is_none(), "truncated to {}", len);
+        }
+    }
+
+    #[test]
+    fn rejects_well_formed_but_wrong_contents() {
+        let data = encode(&sample()).unwrap();
+
+        let mut version = data.clone();
+        version[7] = 2;
+        reseal(&mut version);
+        assert!(parse(&version).is_none());
+
+        let mut extra = data.clone();
+        extra.insert(data.len() - 32, 0);
+        reseal(&mut extra);
+        assert!(parse(&extra).is_none());
+
+        let mut count = data.clone();
+        count[11] += 1;
+        reseal(&mut count);
+        assert!(parse(&count).is_none());
+    }
+
+    #[test]
+    fn refuses_to_write_invalid_hashes() {
+        let mut entries = sample();
+        entries.get_mut("a.txt").unwrap().hash = "not hex".to_string();
+        assert!(encode(&entries).is_err());
+    }
+
+    #[test]
+    fn entries_as_new_as_the_index_are_racy() {
+        let mut index = Index {
+            path: PathBuf::new(),
+            working_dir: PathBuf::new(),
+            written: Some(Timestamp { secs: 100, nanos: 5 }),
+            entries: HashMap::new(),
+            changed: false,
+        };
+        let stat = |secs, nanos| Stat { mtime: Timestamp { secs, nanos }, ctime: Timestamp { secs, nanos }, size: 0, inode: 0 };
+        assert!(!index.is_racy(&stat(100, 4)));
+        assert!(index.is_racy(&stat(100, 5)));
+        assert!(index.is_racy(&stat(101, 0)));
+        index.written = None;
+        assert!(index.is_racy(&stat(0, 0)));
+    }
+}
@@ -1,7 +1,18 @@
-use clap::{App, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, SubCommand};
 use std::process;
 
+use commands::{PullMode, PushMode};
+use config::Scope;
+
+mod backup;
 mod commands;
+mod config;
+mod diff;
+mod gc;
+mod ignore;
+mod index;
+mod merge;
+mod patch;
 mod repository;
 mod utils;
 
@@ -10,13 +21,31 @@ fn main() {
         .version("1.0")
         .author("Your Name")
         .about("A simple version control system")
+        .arg(
+            Arg::with_name("directory")
+                .short("C")
+                .takes_value(true)
+                .value_name("path")
+                .help("Run as if started in <path>"),
+        )
         .subcommand(
             SubCommand::with_name("init")
-                .about("Initialize a new repository"),
+                .about("Initialize a new repository")
+                .arg(
+                    Arg::with_name("bare")
+                        .long("bare")
+                        .help("Create a repository without a working directory, for pushing to"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("add")
                 .about("Add files to staging area")
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Add files even if they match .mini-gitignore"),
+                )
                 .arg(
                     Arg::with_name("paths")
                         .help("Paths to add")
@@ -25,6 +54,55 @@ fn main() {
                         .index(1),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove files from the working tree and the next commit")
+                .arg(
+                    Arg::with_name("cached")
+                        .long("cached")
+                        .help("Only stop tracking, keep the files on disk"),
+                )
+                .arg(
+                    Arg::with_name("recursive")
+                        .short("r")
+                        .help("Allow removing directories recursively"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Remove files even if they have uncommitted changes"),
+                )
+                .arg(
+                    Arg::with_name("paths")
+                        .help("Paths to remove")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mv")
+                .about("Move or rename a tracked file or directory")
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Overwrite an existing destination file"),
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .help("Path to move")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .help("New path or existing directory")
+                        .required(true)
+                        .index(2),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("commit")
                 .about("Commit changes")
@@ -35,6 +113,13 @@ fn main() {
                         .help("Commit message")
                         .required(true)
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("author")
+                        .long("author")
+                        .value_name("NAME <EMAIL>")
+                        .help("Override the configured author")
+                        .takes_value(true),
                 ),
         )
         .subcommand(
@@ -45,17 +130,118 @@ fn main() {
             SubCommand::with_name("history")
                 .about("Show commit history"),
         )
+        .subcommand(
+            SubCommand::with_name("remote")
+                .about("List, add or remove remote repositories")
+                .arg(
+                    Arg::with_name("verbose")
+                        .short("v")
+                        .long("verbose")
+                        .help("Show remote URLs"),
+                )
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Add a remote")
+                        .arg(Arg::with_name("name").help("Remote name").required(true).index(1))
+                        .arg(Arg::with_name("url").help("Path to the other repository").required(true).index(2)),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .alias("rm")
+                        .about("Remove a remote")
+                        .arg(Arg::with_name("name").help("Remote name").required(true).index(1)),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("push")
-                .about("Push changes to remote"),
+                .about("Push a branch to a remote")
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .conflicts_with("force_with_lease")
+                        .help("Overwrite the remote branch even if commits would be lost"),
+                )
+                .arg(
+                    Arg::with_name("force_with_lease")
+                        .long("force-with-lease")
+                        .help("Overwrite the remote branch only if it hasn't moved since the last fetch or push"),
+                )
+                .arg(
+                    Arg::with_name("remote")
+                        .help("Remote to push to (default: origin)")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .help("Branch to push (default: the current branch)")
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("clone")
+                .about("Copy an existing repository into a new working directory")
+                .arg(
+                    Arg::with_name("source")
+                        .help("Path to the repository to clone")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .help("Directory to create (default: named after the source)")
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fetch")
+                .about("Download commits and branches from a remote into remote-tracking refs")
+                .arg(
+                    Arg::with_name("remote")
+                        .help("Remote to fetch from (default: origin)")
+                        .index(1),
+                ),
         )
         .subcommand(
             SubCommand::with_name("pull")
-                .about("Pull changes from remote"),
+                .about("Fetch a branch from a remote and integrate it into the current branch")
+                .arg(
+                    Arg::with_name("ff_only")
+                        .long("ff-only")
+                        .conflicts_with_all(&["merge", "rebase"])
+                        .help("Only fast-forward, fail if the branches have diverged"),
+                )
+                .arg(
+                    Arg::with_name("merge")
+                        .long("merge")
+                        .conflicts_with("rebase")
+                        .help("Merge the remote branch (default)"),
+                )
+                .arg(
+                    Arg::with_name("rebase")
+                        .long("rebase")
+                        .help("Replay local commits on top of the remote branch"),
+                )
+                .arg(
+                    Arg::with_name("remote")
+                        .help("Remote to pull from (default: origin)")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .help("Branch to pull (default: the current branch)")
+                        .index(2),
+                ),
         )
         .subcommand(
             SubCommand::with_name("checkout")
                 .about("Checkout a specific commit")
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Discard local changes that would be overwritten"),
+                )
                 .arg(
                     Arg::with_name("commit_id")
                         .help("Commit ID to checkout")
@@ -63,13 +249,157 @@ fn main() {
                         .index(1),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Get and set repository or global options")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .arg(
+                    Arg::with_name("global")
+                        .long("global")
+                        .global(true)
+                        .conflicts_with("system")
+                        .help("Use the per-user config file"),
+                )
+                .arg(
+                    Arg::with_name("system")
+                        .long("system")
+                        .global(true)
+                        .help("Use the system-wide config file"),
+                )
+                .subcommand(
+                    SubCommand::with_name("get")
+                        .about("Print the value of an option")
+                        .arg(Arg::with_name("key").help("Option name, e.g. user.name").required(true).index(1)),
+                )
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Set an option")
+                        .arg(Arg::with_name("key").help("Option name, e.g. user.name").required(true).index(1))
+                        .arg(Arg::with_name("value").help("New value").required(true).index(2)),
+                )
+                .subcommand(
+                    SubCommand::with_name("unset")
+                        .about("Remove an option")
+                        .arg(Arg::with_name("key").help("Option name, e.g. user.name").required(true).index(1)),
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List all options, lowest precedence first"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backups")
+                .about("List or restore working directory backups taken by checkout")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List backups, newest first"),
+                )
+                .subcommand(
+                    SubCommand::with_name("restore")
+                        .about("Write a backup's files back into the working directory")
+                        .arg(
+                            Arg::with_name("id")
+                                .help("Backup ID or unique prefix")
+                                .required(true)
+                                .index(1),
+                        ),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("loadlast")
-                .about("Checkout the most recent commit"),
+                .about("Checkout the tip of the current branch"),
+        )
+        .subcommand(
+            SubCommand::with_name("branch")
+                .about("List, create, rename or delete branches")
+                .arg(
+                    Arg::with_name("delete")
+                        .short("d")
+                        .long("delete")
+                        .help("Delete a fully merged branch")
+                        .requires("name"),
+                )
+                .arg(
+                    Arg::with_name("force_delete")
+                        .short("D")
+                        .help("Delete a branch even if it is not merged")
+                        .requires("name")
+                        .conflicts_with("delete"),
+                )
+                .arg(
+                    Arg::with_name("move")
+                        .short("m")
+                        .long("move")
+                        .help("Rename a branch (the current one if only a new name is given)")
+                        .requires("name")
+                        .conflicts_with_all(&["delete", "force_delete"]),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .help("Branch name")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .help("Start point, or the new name when renaming")
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("switch")
+                .about("Switch to a branch")
+                .arg(
+                    Arg::with_name("create")
+                        .short("c")
+                        .long("create")
+                        .help("Create the branch at HEAD before switching"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Discard local changes that would be overwritten"),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .help("Branch to switch to")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Merge a branch or commit into the current branch")
+                .arg(
+                    Arg::with_name("abort")
+                        .long("abort")
+                        .help("Abandon the merge in progress"),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .help("Branch or commit to merge")
+                        .required_unless("abort")
+                        .index(1),
+                ),
         )
         .subcommand(
             SubCommand::with_name("diff")
                 .about("Show changes between commits or working directory")
+                .arg(
+                    Arg::with_name("patch")
+                        .short("p")
+                        .long("patch")
+                        .help("Show changes as a unified diff"),
+                )
+                .arg(
+                    Arg::with_name("unified")
+                        .short("U")
+                        .long("unified")
+                        .value_name("N")
+                        .help("Lines of context in the unified diff (implies --patch)")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("commit_id1")
                         .help("First commit ID (optional)")
@@ -81,6 +411,76 @@ fn main() {
                         .index(2),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("apply")
+                .about("Apply a unified diff to the working directory")
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Only report whether the patch applies"),
+                )
+                .arg(
+                    Arg::with_name("index")
+                        .long("index")
+                        .help("Stage the patched files"),
+                )
+                .arg(
+                    Arg::with_name("strip")
+                        .short("p")
+                        .value_name("N")
+                        .help("Leading path components to strip (default 1)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("patchfile")
+                        .help("Patch file, or - to read from stdin")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check-ignore")
+                .about("Show whether paths are excluded by .mini-gitignore")
+                .arg(
+                    Arg::with_name("verbose")
+                        .short("v")
+                        .long("verbose")
+                        .help("Show the matching pattern"),
+                )
+                .arg(
+                    Arg::with_name("paths")
+                        .help("Paths to check")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Verify objects, commits and refs")
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Restore missing or corrupt objects from the working tree"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Delete objects nothing refers to any more")
+                .arg(
+                    Arg::with_name("dry_run")
+                        .short("n")
+                        .long("dry-run")
+                        .help("Only report what would be deleted"),
+                )
+                .arg(
+                    Arg::with_name("grace_days")
+                        .long("grace-days")
+                        .value_name("N")
+                        .help("Keep unreachable objects written in the last N days (default gc.graceDays, or 14)")
+                        .takes_value(true),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("diffdetailed")
                 .about("Show detailed changes between commits or working directory")
@@ -97,9 +497,16 @@ fn main() {
         )
         .get_matches();
 
+    if let Some(dir) = matches.value_of("directory") {
+        if let Err(e) = std::env::set_current_dir(dir) {
+            eprintln!("Error changing to {}: {}", dir, e);
+            process::exit(1);
+        }
+    }
+
     match matches.subcommand() {
-        ("init", Some(_)) => {
-            if let Err(e) = commands::init() {
+        ("init", Some(init_matches)) => {
+            if let Err(e) = commands::init(init_matches.is_present("bare")) {
                 eprintln!("Error initializing repository: {}", e);
                 process::exit(1);
             }
@@ -111,14 +518,36 @@ fn main() {
                 .map(String::from)
                 .collect();
             
-            if let Err(e) = commands::add(&paths) {
+            if let Err(e) = commands::add(&paths, add_matches.is_present("force")) {
                 eprintln!("Error adding files: {}", e);
                 process::exit(1);
             }
         }
+        ("rm", Some(rm_matches)) => {
+            let paths: Vec<String> = rm_matches
+                .values_of("paths")
+                .unwrap()
+                .map(String::from)
+                .collect();
+            let cached = rm_matches.is_present("cached");
+            let recursive = rm_matches.is_present("recursive");
+            let force = rm_matches.is_present("force");
+            if let Err(e) = commands::rm(&paths, cached, recursive, force) {
+                eprintln!("Error removing files: {}", e);
+                process::exit(1);
+            }
+        }
+        ("mv", Some(mv_matches)) => {
+            let source = mv_matches.value_of("source").unwrap();
+            let destination = mv_matches.value_of("destination").unwrap();
+            if let Err(e) = commands::mv(source, destination, mv_matches.is_present("force")) {
+                eprintln!("Error moving files: {}", e);
+                process::exit(1);
+            }
+        }
         ("commit", Some(commit_matches)) => {
             let message = commit_matches.value_of("message").unwrap();
-            if let Err(e) = commands::commit(message) {
+            if let Err(e) = commands::commit(message, commit_matches.value_of("author")) {
                 eprintln!("Error committing changes: {}", e);
                 process::exit(1);
             }
@@ -135,39 +564,221 @@ fn main() {
                 process::exit(1);
             }
         }
-        ("push", Some(_)) => {
-            if let Err(e) = commands::push() {
+        ("remote", Some(remote_matches)) => {
+            let result = match remote_matches.subcommand() {
+                ("add", Some(add_matches)) => {
+                    commands::remote_add(add_matches.value_of("name").unwrap(), add_matches.value_of("url").unwrap())
+                }
+                ("remove", Some(remove_matches)) => commands::remote_remove(remove_matches.value_of("name").unwrap()),
+                _ => commands::remote_list(remote_matches.is_present("verbose")),
+            };
+            if let Err(e) = result {
+                eprintln!("Error managing remotes: {}", e);
+                process::exit(1);
+            }
+        }
+        ("push", Some(push_matches)) => {
+            let remote = push_matches.value_of("remote").unwrap_or("origin");
+            let mode = if push_matches.is_present("force") {
+                PushMode::Force
+            } else if push_matches.is_present("force_with_lease") {
+                PushMode::ForceWithLease
+            } else {
+                PushMode::FastForward
+            };
+            if let Err(e) = commands::push(remote, push_matches.value_of("branch"), mode) {
                 eprintln!("Error pushing changes: {}", e);
                 process::exit(1);
             }
         }
-        ("pull", Some(_)) => {
-            if let Err(e) = commands::pull() {
+        ("clone", Some(clone_matches)) => {
+            let source = clone_matches.value_of("source").unwrap();
+            if let Err(e) = commands::clone(source, clone_matches.value_of("destination")) {
+                eprintln!("Error cloning repository: {}", e);
+                process::exit(1);
+            }
+        }
+        ("fetch", Some(fetch_matches)) => {
+            if let Err(e) = commands::fetch(fetch_matches.value_of("remote").unwrap_or("origin")) {
+                eprintln!("Error fetching changes: {}", e);
+                process::exit(1);
+            }
+        }
+        ("pull", Some(pull_matches)) => {
+            let remote = pull_matches.value_of("remote").unwrap_or("origin");
+            let mode = if pull_matches.is_present("ff_only") {
+                PullMode::FastForwardOnly
+            } else if pull_matches.is_present("rebase") {
+                PullMode::Rebase
+            } else {
+                PullMode::Merge
+            };
+            if let Err(e) = commands::pull(remote, pull_matches.value_of("branch"), mode) {
                 eprintln!("Error pulling changes: {}", e);
                 process::exit(1);
             }
         }
         ("checkout", Some(checkout_matches)) => {
             let commit_id = checkout_matches.value_of("commit_id").unwrap();
-            if let Err(e) = commands::checkout(commit_id) {
+            if let Err(e) = commands::checkout(commit_id, checkout_matches.is_present("force")) {
                 eprintln!("Error checking out commit: {}", e);
                 process::exit(1);
             }
         }
+        ("config", Some(config_matches)) => {
+            let (name, sub_matches) = config_matches.subcommand();
+            let sub_matches = sub_matches.unwrap();
+            // The scope flags may come before or after the action
+            let flag = |name| config_matches.is_present(name) || sub_matches.is_present(name);
+            let scope = if flag("system") {
+                Some(Scope::System)
+            } else if flag("global") {
+                Some(Scope::Global)
+            } else {
+                None
+            };
+            let key = sub_matches.value_of("key").unwrap_or_default();
+            let result = match name {
+                "get" => commands::config_get(key, scope).map(|found| {
+                    if !found {
+                        process::exit(1);
+                    }
+                }),
+                "set" => commands::config_set(key, sub_matches.value_of("value").unwrap(), scope.unwrap_or(Scope::Local)),
+                "unset" => commands::config_unset(key, scope.unwrap_or(Scope::Local)),
+                _ => commands::config_list(scope),
+            };
+            if let Err(e) = result {
+                eprintln!("Error accessing config: {}", e);
+                process::exit(1);
+            }
+        }
+        ("backups", Some(backups_matches)) => {
+            let result = match backups_matches.subcommand() {
+                ("restore", Some(restore_matches)) => {
+                    commands::backups_restore(restore_matches.value_of("id").unwrap())
+                }
+                _ => commands::backups_list(),
+            };
+            if let Err(e) = result {
+                eprintln!("Error handling backups: {}", e);
+                process::exit(1);
+            }
+        }
         ("loadlast", Some(_)) => {
             if let Err(e) = commands::loadlast() {
                 eprintln!("Error loading last commit: {}", e);
                 process::exit(1);
             }
         }
+        ("branch", Some(branch_matches)) => {
+            let name = branch_matches.value_of("name");
+            let target = branch_matches.value_of("target");
+            let result = if branch_matches.is_present("move") {
+                match target {
+                    Some(new_name) => commands::branch_rename(name, new_name),
+                    None => commands::branch_rename(None, name.unwrap()),
+                }
+            } else if branch_matches.is_present("delete") || branch_matches.is_present("force_delete") {
+                commands::branch_delete(name.unwrap(), branch_matches.is_present("force_delete"))
+            } else if let Some(name) = name {
+                commands::branch_create(name, target)
+            } else {
+                commands::branch_list()
+            };
+            if let Err(e) = result {
+                eprintln!("Error managing branches: {}", e);
+                process::exit(1);
+            }
+        }
+        ("switch", Some(switch_matches)) => {
+            let name = switch_matches.value_of("name").unwrap();
+            if let Err(e) = commands::switch(name, switch_matches.is_present("create"), switch_matches.is_present("force")) {
+                eprintln!("Error switching branches: {}", e);
+                process::exit(1);
+            }
+        }
+        ("merge", Some(merge_matches)) => {
+            let result = match merge_matches.value_of("target") {
+                Some(target) if !merge_matches.is_present("abort") => commands::merge(target),
+                _ => commands::merge_abort(),
+            };
+            if let Err(e) = result {
+                eprintln!("Error merging: {}", e);
+                process::exit(1);
+            }
+        }
         ("diff", Some(diff_matches)) => {
             let commit_id1 = diff_matches.value_of("commit_id1");
             let commit_id2 = diff_matches.value_of("commit_id2");
-            if let Err(e) = commands::diff(commit_id1, commit_id2) {
+            let context = diff_matches.value_of("unified").map(|value| match value.parse::<usize>() {
+                Ok(context) => context,
+                Err(_) => {
+                    eprintln!("Error showing diff: invalid context line count '{}'", value);
+                    process::exit(1);
+                }
+            });
+            let patch = diff_matches.is_present("patch") || context.is_some();
+            if let Err(e) = commands::diff(commit_id1, commit_id2, patch, context) {
                 eprintln!("Error showing diff: {}", e);
                 process::exit(1);
             }
         }
+        ("apply", Some(apply_matches)) => {
+            let patch_file = apply_matches.value_of("patchfile").unwrap();
+            let strip = match apply_matches.value_of("strip").unwrap_or("1").parse::<usize>() {
+                Ok(strip) => strip,
+                Err(_) => {
+                    eprintln!("Error applying patch: invalid -p value");
+                    process::exit(1);
+                }
+            };
+            let check = apply_matches.is_present("check");
+            let stage = apply_matches.is_present("index");
+            if let Err(e) = commands::apply(patch_file, check, stage, strip) {
+                eprintln!("Error applying patch: {}", e);
+                process::exit(1);
+            }
+        }
+        ("check-ignore", Some(check_matches)) => {
+            let paths: Vec<String> = check_matches
+                .values_of("paths")
+                .unwrap()
+                .map(String::from)
+                .collect();
+            match commands::check_ignore(&paths, check_matches.is_present("verbose")) {
+                Ok(true) => {}
+                // Like git, exit non-zero when none of the paths are ignored
+                Ok(false) => process::exit(1),
+                Err(e) => {
+                    eprintln!("Error checking ignore rules: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        ("fsck", Some(fsck_matches)) => {
+            match commands::fsck(fsck_matches.is_present("repair")) {
+                Ok(true) => {}
+                Ok(false) => process::exit(1),
+                Err(e) => {
+                    eprintln!("Error checking repository: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        ("gc", Some(gc_matches)) => {
+            let grace_days = gc_matches.value_of("grace_days").map(|value| match value.parse::<usize>() {
+                Ok(days) => days,
+                Err(_) => {
+                    eprintln!("Error collecting garbage: invalid day count '{}'", value);
+                    process::exit(1);
+                }
+            });
+            if let Err(e) = commands::gc(gc_matches.is_present("dry_run"), grace_days) {
+                eprintln!("Error collecting garbage: {}", e);
+                process::exit(1);
+            }
+        }
         ("diffdetailed", Some(diff_matches)) => {
             let commit_id1 = diff_matches.value_of("commit_id1");
             let commit_id2 = diff_matches.value_of("commit_id2");
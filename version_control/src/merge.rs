@@ -0,0 +1,334 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::diff::{self, DiffOp};
+use crate::repository::Repository;
+use crate::utils;
+
+// Outcome for a path whose merged state differs from "ours"
+#[derive(Debug)]
+pub enum MergedFile {
+    // Cleanly merged, content stored under this object hash
+    Clean(String),
+    Deleted,
+    // Content to leave in the working tree for the user to resolve
+    Conflict(Vec<u8>, ConflictKind),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    BothModified,
+    // We deleted the file, they changed it
+    DeletedByUs,
+    // They deleted the file, we changed it
+    DeletedByThem,
+}
+
+impl ConflictKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ConflictKind::BothModified => "both modified",
+            ConflictKind::DeletedByUs => "deleted by us",
+            ConflictKind::DeletedByThem => "deleted by them",
+        }
+    }
+}
+
+pub struct TextMerge {
+    pub content: String,
+    pub conflicts: usize,
+}
+
+// Three-way merge of the trees `ours` and `theirs` against their common `base`.
+// Only paths that need to change relative to `ours` are returned.
+pub fn merge_trees(
+    repo: &Repository,
+    base: &HashMap<String, String>,
+    ours: &HashMap<String, String>,
+    theirs: &HashMap<String, String>,
+    ours_label: &str,
+    theirs_label: &str,
+) -> std::io::Result<BTreeMap<String, MergedFile>> {
+    let paths: BTreeSet<&String> = base.keys().chain(ours.keys()).chain(theirs.keys()).collect();
+    let mut result = BTreeMap::new();
+
+    for path in paths {
+        let base_hash = base.get(path);
+        let our_hash = ours.get(path);
+        let their_hash = theirs.get(path);
+
+        // Same on both sides, or only we changed it
+        if our_hash == their_hash || their_hash == base_hash {
+            continue;
+        }
+
+        // Only they changed it
+        if our_hash == base_hash {
+            let merged = match their_hash {
+                Some(hash) => MergedFile::Clean(hash.clone()),
+                None => MergedFile::Deleted,
+            };
+            result.insert(path.clone(), merged);
+            continue;
+        }
+
+        // Both changed it differently
+        match (our_hash, their_hash) {
+            (Some(our_hash), Some(their_hash)) => {
+                let base_content = match base_hash {
+                    Some(hash) => repo.get_object(hash)?,
+                    None => Vec::new(),
+                };
+                let our_content = repo.get_object(our_hash)?;
+                let their_content = repo.get_object(their_hash)?;
+
+                if utils::is_binary(&base_content) || utils::is_binary(&our_content) || utils::is_binary(&their_content) {
+                    result.insert(path.clone(), MergedFile::Conflict(our_content, ConflictKind::BothModified));
+                    continue;
+                }
+
+                let merged = merge_text(
+                    &String::from_utf8_lossy(&base_content),
+                    &String::from_utf8_lossy(&our_content),
+                    &String::from_utf8_lossy(&their_content),
+                    ours_label,
+                    theirs_label,
+                );
+                if merged.conflicts > 0 {
+                    result.insert(
+                        path.clone(),
+                        MergedFile::Conflict(merged.content.into_bytes(), ConflictKind::BothModified),
+                    );
+                } else {
+                    let content = merged.content.into_bytes();
+                    let hash = utils::calculate_hash_bytes(&content);
+                    repo.write_object(&hash, &content)?;
+                    result.insert(path.clone(), MergedFile::Clean(hash));
+                }
+            }
+            // Modified on one side, deleted on the other: keep the modified version
+            (Some(hash), None) => {
+                let content = repo.get_object(hash)?;
+                result.insert(path.clone(), MergedFile::Conflict(content, ConflictKind::DeletedByThem));
+            }
+            (None, Some(hash)) => {
+                let content = repo.get_object(hash)?;
+                result.insert(path.clone(), MergedFile::Conflict(content, ConflictKind::DeletedByUs));
+            }
+            (None, None) => {}
+        }
+    }
+
+    Ok(result)
+}
+
+// Line-level three-way merge (diff3). Regions changed on only one side are
+// taken from that side; overlapping changes become conflict markers.
+pub fn merge_text(base: &str, ours: &str, theirs: &str, ours_label: &str, theirs_label: &str) -> TextMerge {
+    let base_lines: Vec<&str> = base.split_inclusive('\n').collect();
+    let our_lines: Vec<&str> = ours.split_inclusive('\n').collect();
+    let their_lines: Vec<&str> = theirs.split_inclusive('\n').collect();
+
+    let ours_match = match_lines(&base_lines, &our_lines);
+    let theirs_match = match_lines(&base_lines, &their_lines);
+
+    let mut merge = TextMerge {
+        content: String::new(),
+        conflicts: 0,
+    };
+    let (mut i, mut j, mut k) = (0, 0, 0);
+
+    loop {
+        // Next base line that is unchanged on both sides
+        let stable = (i..base_lines.len()).find(|&l| ours_match[l].is_some() && theirs_match[l].is_some());
+        let (end_base, end_ours, end_theirs) = match stable {
+            Some(l) => (l, ours_match[l].unwrap(), theirs_match[l].unwrap()),
+            None => (base_lines.len(), our_lines.len(), their_lines.len()),
+        };
+
+        if (end_base, end_ours, end_theirs) == (i, j, k) {
+            if stable.is_none() {
+                break;
+            }
+            merge.content.push_str(base_lines[i]);
+            i += 1;
+            j += 1;
+            k += 1;
+            continue;
+        }
+
+        merge_region(
+            &mut merge,
+            &base_lines[i..end_base],
+            &our_lines[j..end_ours],
+            &their_lines[k..end_theirs],
+            ours_label,
+            theirs_label,
+        );
+        i = end_base;
+        j = end_ours;
+        k = end_theirs;
+    }
+
+    merge
+}
+
+fn merge_region(merge: &mut TextMerge, base: &[&str], ours: &[&str], theirs: &[&str], ours_label: &str, theirs_label: &str) {
+    if ours == base {
+        theirs.iter().for_each(|line| merge.content.push_str(line));
+    } else if theirs == base || ours == theirs {
+        ours.iter().for_each(|line| merge.content.push_str(line));
+    } else {
+        merge.conflicts += 1;
+        merge.content.push_str(&format!("<<<<<<< {}\n", ours_label));
+        push_lines(&mut merge.content, ours);
+        merge.content.push_str("=======\n");
+        push_lines(&mut merge.content, theirs);
+        merge.content.push_str(&format!(">>>>>>> {}\n", theirs_label));
+    }
+}
+
+// Append lines, making sure the markers that follow start on their own line
+fn push_lines(content: &mut String, lines: &[&str]) {
+    for line in lines {
+        content.push_str(line);
+    }
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+}
+
+// For each line of `a`, the index of the line of `b` it is paired with in the diff
+fn match_lines(a: &[&str], b: &[&str]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; a.len()];
+    for hunk in diff::diff_lines(a, b) {
+        if hunk.op == DiffOp::Equal {
+            for (x, y) in hunk.old.zip(hunk.new) {
+                matches[x] = Some(y);
+            }
+        }
+    }
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+
+    fn merge(base: &str, ours: &str, theirs: &str) -> (String, usize) {
+        let merged = merge_text(base, ours, theirs, "ours", "theirs");
+        (merged.content, merged.conflicts)
+    }
+
+    #[test]
+    fn takes_changes_made_on_one_side() {
+        let base = "a\nb\nc\nd\ne\n";
+        assert_eq!(merge(base, "a\nB\nc\nd\ne\n", base), ("a\nB\nc\nd\ne\n".to_string(), 0));
+        assert_eq!(merge(base, base, "a\nb\nc\ne\n"), ("a\nb\nc\ne\n".to_string(), 0));
+        // Separate regions changed on each side
+        assert_eq!(merge(base, "A\nb\nc\nd\ne\n", "a\nb\nc\nd\nE\nf\n"), ("A\nb\nc\nd\nE\nf\n".to_string(), 0));
+    }
+
+    #[test]
+    fn identical_changes_merge_cleanly() {
+        let base = "a\nb\nc\n";
+        assert_eq!(merge(base, "a\nX\nc\n", "a\nX\nc\n"), ("a\nX\nc\n".to_string(), 0));
+        assert_eq!(merge(base, "a\nc\n", "a\nc\n"), ("a\nc\n".to_string(), 0));
+    }
+
+    #[test]
+    fn overlapping_changes_conflict() {
+        let (content, conflicts) = merge("a\nb\nc\nd\n", "a\nours\nc\nd\n", "a\ntheirs\nc\nd\n");
+        assert_eq!(conflicts, 1);
+        assert_eq!(content, "a\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\nc\nd\n");
+
+        // Each overlapping region gets its own markers, clean ones merge around them
+        let base = "1\n2\n3\n4\n5\n6\n7\n";
+        let (content, conflicts) = merge(base, "x\n2\n3\n4\n5\nB\n7\n", "y\n2\n3\nA\n5\nC\n7\n");
+        assert_eq!(conflicts, 2);
+        assert_eq!(
+            content,
+            "<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\n2\n3\nA\n5\n\
+             <<<<<<< ours\nB\n=======\nC\n>>>>>>> theirs\n7\n"
+        );
+    }
+
+    #[test]
+    fn add_add_merges_against_an_empty_base() {
+        assert_eq!(merge("", "same\n", "same\n"), ("same\n".to_string(), 0));
+        assert_eq!(merge("", "x\n", "y\n"), ("<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\n".to_string(), 1));
+    }
+
+    #[test]
+    fn files_without_a_trailing_newline() {
+        assert_eq!(merge("a\nm\nb", "A\nm\nb", "a\nm\nB"), ("A\nm\nB".to_string(), 0));
+        assert_eq!(merge("a\nb\n", "a\nb\n", "a\nb"), ("a\nb".to_string(), 0));
+        // Markers still start on a line of their own
+        assert_eq!(merge("z", "x", "y"), ("<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\n".to_string(), 1));
+    }
+
+    #[test]
+    fn merges_trees() {
+        let dir = TempDir::new("merge-trees");
+        let repo = Repository::new(dir.0.clone());
+        let store = |content: &str| {
+            let hash = utils::calculate_hash_bytes(content.as_bytes());
+            repo.write_object(&hash, content.as_bytes()).unwrap();
+            hash
+        };
+        let tree = |files: &[(&str, &str)]| -> HashMap<String, String> {
+            files.iter().map(|(path, content)| (path.to_string(), store(content))).collect()
+        };
+
+        let base = tree(&[
+            ("ours-only", "1\n"),
+            ("theirs-only", "1\n"),
+            ("both-same", "1\n"),
+            ("deleted-by-them", "1\n"),
+            ("modify-delete", "1\n"),
+            ("delete-modify", "1\n"),
+            ("text", "a\nb\nc\nd\n"),
+            ("binary", "\0base"),
+        ]);
+        let ours = tree(&[
+            ("ours-only", "2\n"),
+            ("theirs-only", "1\n"),
+            ("both-same", "2\n"),
+            ("deleted-by-them", "1\n"),
+            ("modify-delete", "2\n"),
+            ("text", "A\nb\nc\nd\n"),
+            ("binary", "\0ours"),
+            ("added", "x\n"),
+        ]);
+        let theirs = tree(&[
+            ("ours-only", "1\n"),
+            ("theirs-only", "3\n"),
+            ("both-same", "2\n"),
+            ("delete-modify", "3\n"),
+            ("text", "a\nb\nc\nD\n"),
+            ("binary", "\0theirs"),
+            ("added", "y\n"),
+        ]);
+        let merged = merge_trees(&repo, &base, &ours, &theirs, "ours", "theirs").unwrap();
+
+        let paths: Vec<&str> = merged.keys().map(String::as_str).collect();
+        assert_eq!(paths, ["added", "binary", "delete-modify", "deleted-by-them", "modify-delete", "text", "theirs-only"]);
+        assert!(matches!(&merged["theirs-only"], MergedFile::Clean(hash) if *hash == theirs["theirs-only"]));
+        assert!(matches!(merged["deleted-by-them"], MergedFile::Deleted));
+        match &merged["text"] {
+            MergedFile::Clean(hash) => assert_eq!(repo.get_object(hash).unwrap(), b"A\nb\nc\nD\n"),
+            other => panic!("text merged to {:?}", other),
+        }
+        assert!(matches!(&merged["modify-delete"], MergedFile::Conflict(content, ConflictKind::DeletedByThem) if content == b"2\n"));
+        assert!(matches!(&merged["delete-modify"], MergedFile::Conflict(content, ConflictKind::DeletedByUs) if content == b"3\n"));
+        assert!(matches!(&merged["binary"], MergedFile::Conflict(content, ConflictKind::BothModified) if content == b"\0ours"));
+        assert!(matches!(
+            &merged["added"],
+            MergedFile::Conflict(content, ConflictKind::BothModified)
+                if content == b"<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\n"
+        ));
+    }
+}
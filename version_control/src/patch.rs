@@ -0,0 +1,427 @@
+// Parsing and applying unified diffs, as produced by `diff --patch`
+
+// Context lines GNU patch is willing to ignore at each end of a hunk
+const MAX_FUZZ: usize = 2;
+
+#[derive(Debug, Clone)]
+pub enum HunkLine {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_count: usize,
+    pub new_start: usize,
+    pub new_count: usize,
+    pub lines: Vec<HunkLine>,
+}
+
+// Changes to one file; a missing path means the file is created or deleted
+#[derive(Debug)]
+pub struct FilePatch {
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HunkResult {
+    // 1-based line the hunk ended up at, distance from where it claimed to be
+    Applied { line: usize, offset: isize, fuzz: usize },
+    Rejected,
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+// Split a patch into per-file changes, stripping `strip` leading path components
+pub fn parse_patch(text: &str, strip: usize) -> std::io::Result<Vec<FilePatch>> {
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let mut patches = Vec::new();
+    let mut index = 0;
+
+    while index < lines.len() {
+        let line = lines[index];
+        if line.starts_with("Binary files ") || line.starts_with("GIT binary patch") {
+            return Err(invalid("Binary patches are not supported".to_string()));
+        }
+        if !line.starts_with("--- ") || !lines.get(index + 1).is_some_and(|l| l.starts_with("+++ ")) {
+            index += 1;
+            continue;
+        }
+
+        let old_path = parse_path(&line[4..], strip)?;
+        let new_path = parse_path(&lines[index + 1][4..], strip)?;
+        if old_path.is_none() && new_path.is_none() {
+            return Err(invalid(format!("Patch header without a file name at line {}", index + 1)));
+        }
+        index += 2;
+
+        let mut hunks = Vec::new();
+        while index < lines.len() && lines[index].starts_with("@@ ") {
+            let (hunk, next) = parse_hunk(&lines, index)?;
+            hunks.push(hunk);
+            index = next;
+        }
+
+        patches.push(FilePatch {
+            old_path,
+            new_path,
+            hunks,
+        });
+    }
+
+    Ok(patches)
+}
+
+fn parse_path(header: &str, strip: usize) -> std::io::Result<Option<String>> {
+    // Drop the optional timestamp that diff(1) puts after a tab
+    let raw = header.trim_end_matches(['\n', '\r']).split('\t').next().unwrap_or("").trim();
+    if raw == "/dev/null" {
+        return Ok(None);
+    }
+
+    let components: Vec<&str> = raw.split('/').filter(|c| !c.is_empty()).collect();
+    if components.len() <= strip {
+        return Err(invalid(format!("Cannot strip {} components from path '{}'", strip, raw)));
+    }
+    let components = &components[strip..];
+    if (raw.starts_with('/') && strip == 0) || components.contains(&"..") {
+        return Err(invalid(format!("Refusing to patch path outside the repository: {}", raw)));
+    }
+    // Like git apply and .git/, never touch the repository's own files; case
+    // is ignored for filesystems that ignore it too
+    if components.iter().any(|c| c.eq_ignore_ascii_case(".mini-git")) {
+        return Err(invalid(format!("Refusing to patch path inside .mini-git: {}", raw)));
+    }
+    Ok(Some(components.join("/")))
+}
+
+// Parse the hunk starting at `start`; returns it with the index of the next line
+fn parse_hunk(lines: &[&str], start: usize) -> std::io::Result<(Hunk, usize)> {
+    let header = lines[start].trim_end();
+    let ranges: Vec<&str> = header
+        .strip_prefix("@@ ")
+        .and_then(|rest| rest.split(" @@").next())
+        .map(|ranges| ranges.split(' ').collect())
+        .unwrap_or_default();
+    let bad_header = || invalid(format!("Malformed hunk header at line {}: {}", start + 1, header));
+    if ranges.len() != 2 {
+        return Err(bad_header());
+    }
+    let (old_start, old_count) = parse_range(ranges[0].strip_prefix('-').ok_or_else(bad_header)?).ok_or_else(bad_header)?;
+    let (new_start, new_count) = parse_range(ranges[1].strip_prefix('+').ok_or_else(bad_header)?).ok_or_else(bad_header)?;
+
+    let mut hunk = Hunk {
+        old_start,
+        old_count,
+        new_start,
+        new_count,
+        lines: Vec::new(),
+    };
+    let (mut old_seen, mut new_seen) = (0, 0);
+    let mut index = start + 1;
+
+    while index < lines.len() && (old_seen < old_count || new_seen < new_count) {
+        let line = lines[index];
+        let content = line.get(1..).unwrap_or("").to_string();
+        match line.chars().next() {
+            Some(' ') => {
+                hunk.lines.push(HunkLine::Context(content));
+                old_seen += 1;
+                new_seen += 1;
+            }
+            // Some tools drop the space on blank context lines
+            Some('\n') | Some('\r') => {
+                hunk.lines.push(HunkLine::Context(line.to_string()));
+                old_seen += 1;
+                new_seen += 1;
+            }
+            Some('-') => {
+                hunk.lines.push(HunkLine::Remove(content));
+                old_seen += 1;
+            }
+            Some('+') => {
+                hunk.lines.push(HunkLine::Add(content));
+                new_seen += 1;
+            }
+            Some('\\') => strip_newline(&mut hunk.lines),
+            _ => return Err(invalid(format!("Unexpected line in hunk at line {}", index + 1))),
+        }
+        index += 1;
+    }
+
+    if old_seen != old_count || new_seen != new_count {
+        return Err(invalid(format!("Truncated hunk at line {}", start + 1)));
+    }
+
+    // "\ No newline at end of file" after the hunk's last line
+    if lines.get(index).is_some_and(|l| l.starts_with('\\')) {
+        strip_newline(&mut hunk.lines);
+        index += 1;
+    }
+
+    Ok((hunk, index))
+}
+
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    match range.split_once(',') {
+        Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+        None => Some((range.parse().ok()?, 1)),
+    }
+}
+
+fn strip_newline(lines: &mut [HunkLine]) {
+    if let Some(HunkLine::Context(text) | HunkLine::Remove(text) | HunkLine::Add(text)) = lines.last_mut() {
+        if text.ends_with('\n') {
+            text.pop();
+            if text.ends_with('\r') {
+                text.pop();
+            }
+        }
+    }
+}
+
+// Apply hunks in order, searching around the expected position and dropping up
+// to MAX_FUZZ context lines at either end when the exact context isn't found
+pub fn apply_hunks(content: &str, hunks: &[Hunk]) -> (String, Vec<HunkResult>) {
+    let mut lines: Vec<String> = content.split_inclusive('\n').map(String::from).collect();
+    let mut results = Vec::new();
+    // Lines before this index were produced by earlier hunks and are off limits
+    let mut floor = 0;
+    let mut drift: isize = 0;
+
+    for hunk in hunks {
+        match locate_hunk(&lines, hunk, floor, drift) {
+            Some((position, fuzz_front, fuzz_back)) => {
+                let old_lines = side_lines(hunk, false);
+                let new_lines = side_lines(hunk, true);
+                let old_len = old_lines.len() - fuzz_front - fuzz_back;
+                let replacement: Vec<String> = new_lines[fuzz_front..new_lines.len() - fuzz_back]
+                    .iter()
+                    .map(|l| l.to_string())
+                    .collect();
+                let replacement_len = replacement.len();
+                lines.splice(position..position + old_len, replacement);
+
+                let expected = hunk_index(hunk.old_start, old_lines.len()) as isize + drift + fuzz_front as isize;
+                results.push(HunkResult::Applied {
+                    line: position + 1,
+                    offset: position as isize - expected,
+                    fuzz: fuzz_front.max(fuzz_back),
+                });
+                drift += replacement_len as isize - old_len as isize;
+                floor = position + replacement_len;
+            }
+            None => results.push(HunkResult::Rejected),
+        }
+    }
+
+    (lines.concat(), results)
+}
+
+// 0-based index of a hunk's first line; empty ranges name the line before
+fn hunk_index(start: usize, count: usize) -> usize {
+    if count == 0 {
+        start
+    } else {
+        start.saturating_sub(1)
+    }
+}
+
+// Lines of the hunk as they read before (`new` false) or after the change
+fn side_lines(hunk: &Hunk, new: bool) -> Vec<&str> {
+    hunk.lines
+        .iter()
+        .filter_map(|line| match line {
+            HunkLine::Context(text) => Some(text.as_str()),
+            HunkLine::Remove(text) if !new => Some(text.as_str()),
+            HunkLine::Add(text) if new => Some(text.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn locate_hunk(lines: &[String], hunk: &Hunk, floor: usize, drift: isize) -> Option<(usize, usize, usize)> {
+    let old_lines = side_lines(hunk, false);
+    let leading_context = hunk.lines.iter().take_while(|l| matches!(l, HunkLine::Context(_))).count();
+    let trailing_context = hunk.lines.iter().rev().take_while(|l| matches!(l, HunkLine::Context(_))).count();
+    let expected = hunk_index(hunk.old_start, old_lines.len()) as isize + drift;
+
+    for fuzz in 0..=MAX_FUZZ {
+        let fuzz_front = fuzz.min(leading_context);
+        let fuzz_back = fuzz.min(trailing_context);
+        if fuzz > 0 && fuzz_front + fuzz_back == 0 {
+            break;
+        }
+        if fuzz_front + fuzz_back > old_lines.len() {
+            break;
+        }
+        let needle = &old_lines[fuzz_front..old_lines.len() - fuzz_back];
+        let target = expected + fuzz_front as isize;
+
+        // Try the expected position first, then move outwards
+        let max_offset = lines.len() as isize + 1;
+        let candidates = std::iter::once(target).chain((1..=max_offset).flat_map(|d| [target - d, target + d]));
+        for candidate in candidates {
+            if candidate >= floor as isize && matches_at(lines, needle, candidate as usize) {
+                return Some((candidate as usize, fuzz_front, fuzz_back));
+            }
+        }
+    }
+    None
+}
+
+fn matches_at(lines: &[String], needle: &[&str], position: usize) -> bool {
+    position + needle.len() <= lines.len()
+        && needle.iter().zip(&lines[position..]).all(|(a, b)| *a == b.as_str())
+}
+
+// Render hunks in unified format, for writing out rejects
+pub fn format_hunks(old_path: &str, new_path: &str, hunks: &[&Hunk]) -> String {
+    let mut output = format!("--- {}\n+++ {}\n", old_path, new_path);
+    for hunk in hunks {
+        output.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            hunk.old_start, hunk.old_count, hunk.new_start, hunk.new_count
+        ));
+        for line in &hunk.lines {
+            let (prefix, text) = match line {
+                HunkLine::Context(text) => (' ', text),
+                HunkLine::Remove(text) => ('-', text),
+                HunkLine::Add(text) => ('+', text),
+            };
+            output.push(prefix);
+            output.push_str(text);
+            if !text.ends_with('\n') {
+                output.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::{unified_diff, DEFAULT_CONTEXT};
+
+    // Diff two versions, parse the diff back and apply it to the old text
+    fn round_trip(old: Option<&str>, new: Option<&str>) {
+        let diff = unified_diff(old, new, "dir/file.txt", DEFAULT_CONTEXT);
+        let patches = parse_patch(&diff, 1).unwrap();
+        assert_eq!(patches.len(), 1, "{}", diff);
+        let patch = &patches[0];
+        assert_eq!(patch.old_path.as_deref(), old.map(|_| "dir/file.txt"));
+        assert_eq!(patch.new_path.as_deref(), new.map(|_| "dir/file.txt"));
+
+        let (result, hunks) = apply_hunks(old.unwrap_or(""), &patch.hunks);
+        assert_eq!(result, new.unwrap_or(""), "{}", diff);
+        for hunk in hunks {
+            assert!(matches!(hunk, HunkResult::Applied { offset: 0, fuzz: 0, .. }), "{:?} in {}", hunk, diff);
+        }
+    }
+
+    fn lines(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("line {}\n", i)).collect()
+    }
+
+    #[test]
+    fn round_trips_single_line_edits() {
+        for len in [1, 2, 5, 12] {
+            let base = lines(len);
+            for i in 0..len {
+                let mut deleted = base.clone();
+                deleted.remove(i);
+                let mut inserted = base.clone();
+                inserted.insert(i, "new\n".to_string());
+                let mut replaced = base.clone();
+                replaced[i] = "changed\n".to_string();
+
+                for new in [deleted, inserted, replaced] {
+                    round_trip(Some(&base.concat()), Some(&new.concat()));
+                    round_trip(Some(&new.concat()), Some(&base.concat()));
+                }
+            }
+            let mut appended = base.clone();
+            appended.push("end\n".to_string());
+            round_trip(Some(&base.concat()), Some(&appended.concat()));
+        }
+    }
+
+    #[test]
+    fn round_trips_multiple_hunks() {
+        let old = lines(40).concat();
+        let new = old.replace("line 3\n", "three\n").replace("line 20\n", "").replace("line 35\n", "a\nb\n");
+        assert_eq!(parse_patch(&unified_diff(Some(&old), Some(&new), "f", DEFAULT_CONTEXT), 1).unwrap()[0].hunks.len(), 3);
+        round_trip(Some(&old), Some(&new));
+    }
+
+    #[test]
+    fn round_trips_missing_newline_at_end() {
+        round_trip(Some("a\nb\n"), Some("a\nb"));
+        round_trip(Some("a\nb"), Some("a\nb\n"));
+        round_trip(Some("a\nb"), Some("a\nc"));
+        round_trip(Some("a"), Some("b"));
+    }
+
+    #[test]
+    fn round_trips_created_and_deleted_files() {
+        round_trip(None, Some("one\ntwo\n"));
+        round_trip(Some("one\ntwo\n"), None);
+        round_trip(None, Some("no newline"));
+    }
+
+    #[test]
+    fn applies_hunks_with_offset_and_fuzz() {
+        let old = lines(10).concat();
+        let new = old.replace("line 5\n", "five\n");
+        let diff = unified_diff(Some(&old), Some(&new), "f", DEFAULT_CONTEXT);
+        let hunks = &parse_patch(&diff, 1).unwrap()[0].hunks;
+
+        // Two lines added above the hunk move it down
+        let shifted = format!("x\ny\n{}", old);
+        let (result, results) = apply_hunks(&shifted, hunks);
+        assert_eq!(result, format!("x\ny\n{}", new));
+        assert_eq!(results, [HunkResult::Applied { line: 5, offset: 2, fuzz: 0 }]);
+
+        // Edited outer context is tolerated
+        let drifted = old.replace("line 2\n", "other\n");
+        let (result, results) = apply_hunks(&drifted, hunks);
+        assert_eq!(result, new.replace("line 2\n", "other\n"));
+        assert_eq!(results, [HunkResult::Applied { line: 4, offset: 0, fuzz: 1 }]);
+
+        // The line being changed is gone
+        let (result, results) = apply_hunks(&old.replace("line 5\n", "gone\n"), hunks);
+        assert_eq!(result, old.replace("line 5\n", "gone\n"));
+        assert_eq!(results, [HunkResult::Rejected]);
+    }
+
+    #[test]
+    fn format_hunks_parses_back() {
+        let old = "a\nb\nc\n";
+        let diff = unified_diff(Some(old), Some("a\nc"), "f", DEFAULT_CONTEXT);
+        let hunks = parse_patch(&diff, 1).unwrap().remove(0).hunks;
+        let rejects = format_hunks("a/f", "b/f", &hunks.iter().collect::<Vec<_>>());
+        let reparsed = parse_patch(&rejects, 1).unwrap().remove(0).hunks;
+        assert_eq!(apply_hunks(old, &reparsed).0, "a\nc");
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(parse_patch("--- a/../x\n+++ b/../x\n", 1).is_err());
+        assert!(parse_patch("--- /etc/passwd\n+++ /etc/passwd\n", 0).is_err());
+        assert!(parse_patch("--- a/.mini-git/HEAD\n+++ b/.mini-git/HEAD\n", 1).is_err());
+        assert!(parse_patch("--- /dev/null\n+++ b/sub/.MINI-GIT/HEAD2\n", 1).is_err());
+        assert!(parse_patch("--- a\n+++ b\n", 1).is_err());
+        assert!(parse_patch("--- a/f\n+++ b/f\n@@ -1 +1 @@\n", 1).is_err());
+        assert!(parse_patch("--- a/f\n+++ b/f\n@@ bogus @@\n", 1).is_err());
+        assert!(parse_patch("Binary files a/f and b/f differ\n", 1).is_err());
+        // Text around the patch is ignored
+        assert_eq!(parse_patch("commit message\n\n--- a/f\n+++ b/f\n", 1).unwrap()[0].new_path.as_deref(), Some("f"));
+    }
+}
@@ -1,10 +1,30 @@
-use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use chrono::{DateTime, FixedOffset, Local, SecondsFormat, Utc};
+use std::env;
+use std::fmt;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::fs;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+use crate::config::Config;
+use crate::merge::ConflictKind;
 use crate::utils;
 
+// Bumped whenever the on-disk layout changes so `load` knows what to migrate
+const REPOSITORY_VERSION: u32 = 6;
+
+pub const DEFAULT_BRANCH: &str = "master";
+
+// What HEAD points at: a branch under refs/heads or a bare commit ID
+#[derive(Debug, Clone, PartialEq)]
+pub enum Head {
+    Branch(String),
+    Detached(String),
+}
+
 // Create a separate struct for backwards compatibility
 #[derive(Debug, Serialize, Deserialize)]
 struct OldRepository {
@@ -13,99 +33,489 @@ struct OldRepository {
     pub working_dir: PathBuf,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// Layout used while objects were embedded in repository.json
+#[derive(Debug, Deserialize)]
+struct InlineObjectsRepository {
+    pub commits: Vec<Commit>,
+    pub staging: HashMap<String, String>,
+    pub objects: HashMap<String, Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Commit {
     pub timestamp: DateTime<Utc>,
     pub id: String,
+    // Empty for a root commit, two or more for a merge
+    #[serde(default)]
+    pub parents: Vec<String>,
     pub message: String,
     pub files: HashMap<String, String>,
+    // Missing on commits made before identities were recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<Signature>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub committer: Option<Signature>,
+}
+
+// Who made a change and when, in their local timezone
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    pub time: DateTime<FixedOffset>,
+}
+
+impl Signature {
+    // Parse "Name <email>", as given to `commit --author`
+    pub fn parse(text: &str, time: DateTime<FixedOffset>) -> std::io::Result<Self> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Expected \"Name <email>\", got: {}", text),
+            )
+        };
+        let (name, rest) = text.split_once('<').ok_or_else(invalid)?;
+        let email = rest.strip_suffix('>').ok_or_else(invalid)?;
+        if name.trim().is_empty() || email.contains(['<', '>']) {
+            return Err(invalid());
+        }
+        Ok(Signature {
+            name: name.trim().to_string(),
+            email: email.trim().to_string(),
+            time,
+        })
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} <{}>", self.name, self.email)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Repository {
+    pub version: u32,
     pub commits: Vec<Commit>,
     pub staging: HashMap<String, String>,
+    // Tracked paths staged for deletion in the next commit
+    #[serde(default)]
+    pub removed: HashSet<String>,
+    // Set while a merge is in progress, cleared by the commit that concludes it
+    #[serde(default)]
+    pub merge_head: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_conflicts")]
+    pub conflicts: HashMap<String, ConflictKind>,
+    // Random IDs from before commits were content addressed, mapped to their new IDs
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    // Found when loading rather than stored, so the project can be moved
+    #[serde(skip)]
     pub working_dir: PathBuf,
-    pub objects: HashMap<String, Vec<u8>>,
+    // Usually <working_dir>/.mini-git, unless overridden by MINI_GIT_DIR
+    #[serde(skip)]
+    git_dir: PathBuf,
+    // A bare repository keeps its files directly in `working_dir` and has no checkout
+    #[serde(skip)]
+    pub bare: bool,
+    // Held from load until the repository is dropped, so concurrent commands
+    // can't overwrite each other's changes
+    #[serde(skip)]
+    lock: Option<RepositoryLock>,
+}
+
+// Conflicts used to be stored as a list of paths, all modified on both sides
+fn deserialize_conflicts<'de, D>(deserializer: D) -> Result<HashMap<String, ConflictKind>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Conflicts {
+        Kinds(HashMap<String, ConflictKind>),
+        Paths(Vec<String>),
+    }
+    Ok(match Conflicts::deserialize(deserializer)? {
+        Conflicts::Kinds(kinds) => kinds,
+        Conflicts::Paths(paths) => paths.into_iter().map(|path| (path, ConflictKind::BothModified)).collect(),
+    })
+}
+
+const LOCK_FILE: &str = "repository.lock";
+
+// .mini-git/repository.lock, holding the owner's process ID and when it was
+// taken. Removed on drop; a process that is killed leaves it behind.
+#[derive(Debug)]
+struct RepositoryLock {
+    path: PathBuf,
+}
+
+impl RepositoryLock {
+    fn acquire(git_dir: &Path) -> std::io::Result<Self> {
+        let path = git_dir.join(LOCK_FILE);
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                let lock = RepositoryLock { path };
+                writeln!(file, "{}\n{}", std::process::id(), Utc::now().to_rfc3339())?;
+                Ok(lock)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(lock_held_error(&path)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for RepositoryLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn lock_held_error(path: &Path) -> std::io::Error {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    let mut lines = content.lines();
+    let pid = lines.next().and_then(|line| line.trim().parse::<u32>().ok());
+    let since = lines.next().unwrap_or("an unknown time");
+
+    let message = match pid {
+        Some(pid) if process_running(pid) == Some(false) => format!(
+            "Stale lock {}: process {} took it at {} and is no longer running.\n\
+             If no other mini-git command is running, remove the file and try again.",
+            path.display(),
+            pid,
+            since
+        ),
+        Some(pid) => format!(
+            "Repository is locked by another mini-git command (process {}, since {}).\n\
+             If that process is gone, remove {} and try again.",
+            pid,
+            since,
+            path.display()
+        ),
+        None => format!(
+            "Repository is locked: {}\n\
+             If no other mini-git command is running, remove the file and try again.",
+            path.display()
+        ),
+    };
+    std::io::Error::new(std::io::ErrorKind::ResourceBusy, message)
+}
+
+// Whether a process exists, when that can be told (Linux /proc)
+fn process_running(pid: u32) -> Option<bool> {
+    let proc_dir = Path::new("/proc");
+    proc_dir.join("self").exists().then(|| proc_dir.join(pid.to_string()).exists())
+}
+
+impl Commit {
+    // Text the commit ID is hashed from. Every field is written out in a fixed
+    // order, so identical commits get identical IDs wherever they are created.
+    pub fn canonical_form(&self) -> String {
+        let mut text = String::new();
+        let mut files: Vec<(&String, &String)> = self.files.iter().collect();
+        files.sort();
+        for (path, hash) in files {
+            text.push_str(&format!("file {} {}\n", hash, path));
+        }
+        for parent in &self.parents {
+            text.push_str(&format!("parent {}\n", parent));
+        }
+        // Commits without identities keep the IDs they were given before
+        for (label, signature) in [("author", &self.author), ("committer", &self.committer)] {
+            if let Some(signature) = signature {
+                text.push_str(&format!(
+                    "{} {} {} {}\n",
+                    label,
+                    signature,
+                    signature.time.timestamp(),
+                    signature.time.format("%z")
+                ));
+            }
+        }
+        text.push_str(&format!(
+            "timestamp {}\n",
+            self.timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true)
+        ));
+        text.push('\n');
+        text.push_str(&self.message);
+        text
+    }
+
+    pub fn compute_id(&self) -> String {
+        utils::calculate_hash_bytes(self.canonical_form().as_bytes())
+    }
 }
 
 impl Repository {
     pub fn new(working_dir: PathBuf) -> Self {
         Repository {
+            version: REPOSITORY_VERSION,
             commits: Vec::new(),
             staging: HashMap::new(),
+            removed: HashSet::new(),
+            merge_head: None,
+            conflicts: HashMap::new(),
+            aliases: HashMap::new(),
+            git_dir: working_dir.join(".mini-git"),
             working_dir,
-            objects: HashMap::new(),
+            bare: false,
+            lock: None,
+        }
+    }
+
+    pub fn new_bare(dir: PathBuf) -> Self {
+        Repository {
+            git_dir: dir.clone(),
+            bare: true,
+            ..Repository::new(dir)
         }
     }
 
+    pub fn repo_dir(&self) -> PathBuf {
+        self.git_dir.clone()
+    }
+
     pub fn save(&self) -> std::io::Result<()> {
-        let repo_dir = self.working_dir.join(".mini-git");
+        let repo_dir = self.repo_dir();
         fs::create_dir_all(&repo_dir)?;
         let repo_file = repo_dir.join("repository.json");
         let serialized = serde_json::to_string_pretty(self)?;
-        fs::write(repo_file, serialized)?;
-        Ok(())
+        utils::write_file_atomic(&repo_file, serialized.as_bytes())
     }
 
     pub fn load(working_dir: PathBuf) -> std::io::Result<Self> {
-        let repo_file = working_dir.join(".mini-git/repository.json");
+        Self::load_from(working_dir.join(".mini-git"), working_dir)
+    }
+
+    fn load_from(git_dir: PathBuf, working_dir: PathBuf) -> std::io::Result<Self> {
+        let lock = if git_dir.is_dir() { Some(RepositoryLock::acquire(&git_dir)?) } else { None };
+        let repo_file = git_dir.join("repository.json");
         if !repo_file.exists() {
-            return Ok(Repository::new(working_dir));
+            return Ok(Repository {
+                git_dir,
+                lock,
+                ..Repository::new(working_dir)
+            });
         }
 
         // Try to read the file content
         let content = fs::read_to_string(&repo_file)?;
 
+        let mut repo = Self::parse(&content, &git_dir, working_dir.clone())?;
+        repo.working_dir = working_dir;
+        repo.git_dir = git_dir;
+        repo.lock = lock;
+        repo.finish_load()
+    }
+
+    // Find the repository the current directory belongs to: $MINI_GIT_DIR
+    // when set, with the current directory as the working tree, otherwise the
+    // nearest directory upwards that has a .mini-git
+    pub fn discover() -> std::io::Result<Self> {
+        let current_dir = env::current_dir()?;
+        if let Some(git_dir) = env::var_os("MINI_GIT_DIR").filter(|dir| !dir.is_empty()) {
+            let git_dir = current_dir.join(git_dir);
+            if !git_dir.join("repository.json").is_file() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("MINI_GIT_DIR is not a mini-git repository: {}", git_dir.display()),
+                ));
+            }
+            return Self::load_from(git_dir, current_dir);
+        }
+
+        for dir in current_dir.ancestors() {
+            if dir.join(".mini-git").is_dir() {
+                return Self::load(dir.to_path_buf());
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Not a mini-git repository (or any of the parent directories)",
+        ))
+    }
+
+    // Open another repository by path, either a working copy or a bare repository
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        if path.join(".mini-git/repository.json").is_file() {
+            return Self::load(path.to_path_buf());
+        }
+        // The .mini-git directory of a working copy, rather than the copy itself
+        if let Some(parent) = path.parent().filter(|_| path.ends_with(".mini-git")) {
+            if path.join("repository.json").is_file() {
+                return Self::load(parent.to_path_buf());
+            }
+        }
+        if path.join("repository.json").is_file() {
+            let mut repo = Self::load_from(path.to_path_buf(), path.to_path_buf())?;
+            repo.bare = true;
+            return Ok(repo);
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Not a mini-git repository: {}", path.display()),
+        ))
+    }
+
+    // Bring a freshly parsed repository up to the current layout
+    fn finish_load(mut self) -> std::io::Result<Self> {
+        if self.version < REPOSITORY_VERSION {
+            self.upgrade()?;
+            self.save()?;
+        }
+
+        Ok(self)
+    }
+
+    fn parse(content: &str, git_dir: &Path, working_dir: PathBuf) -> std::io::Result<Self> {
         // First try to deserialize as the new format
-        match serde_json::from_str::<Repository>(&content) {
-            Ok(repo) => Ok(repo),
-            Err(_) => {
-                // If that fails, try to deserialize as old format and migrate
-                let old_repo: OldRepository = serde_json::from_str(&content)?;
-                
-                // Create new repository with migrated data
-                let mut new_repo = Repository {
-                    commits: old_repo.commits,
-                    staging: old_repo.staging,
-                    working_dir,
-                    objects: HashMap::new(),
-                };
+        if let Ok(repo) = serde_json::from_str::<Repository>(content) {
+            return Ok(repo);
+        }
 
-                // Optionally rebuild the objects store from working directory
-                new_repo.rebuild_objects_store()?;
+        // Objects used to live inside repository.json; move them into the object store
+        if let Ok(inline_repo) = serde_json::from_str::<InlineObjectsRepository>(content) {
+            let new_repo = Repository {
+                version: 1,
+                commits: inline_repo.commits,
+                staging: inline_repo.staging,
+                git_dir: git_dir.to_path_buf(),
+                ..Repository::new(working_dir)
+            };
 
-                // Save the migrated repository
-                new_repo.save()?;
+            for (hash, content) in &inline_repo.objects {
+                new_repo.write_object(hash, content)?;
+            }
+
+            return Ok(new_repo);
+        }
 
-                Ok(new_repo)
+        // If that fails, try to deserialize as old format and migrate
+        let old_repo: OldRepository = serde_json::from_str(content)?;
+
+        // Create new repository with migrated data
+        let new_repo = Repository {
+            version: 1,
+            commits: old_repo.commits,
+            staging: old_repo.staging,
+            git_dir: git_dir.to_path_buf(),
+            ..Repository::new(working_dir)
+        };
+
+        // Optionally rebuild the objects store from working directory
+        new_repo.rebuild_objects_store()?;
+
+        Ok(new_repo)
+    }
+
+    // Apply each layout migration in turn until the repository is current
+    fn upgrade(&mut self) -> std::io::Result<()> {
+        if self.version < 2 {
+            // Commits used to hold only the files staged for them, so replay
+            // the history in order to reconstruct full snapshots
+            let mut tree: HashMap<String, String> = HashMap::new();
+            for commit in &mut self.commits {
+                tree.extend(commit.files.drain());
+                commit.files = tree.clone();
+            }
+        }
+
+        if self.version < 3 {
+            // History used to be the order of the commits vector
+            let mut previous: Option<String> = None;
+            for commit in &mut self.commits {
+                commit.parents = previous.iter().cloned().collect();
+                previous = Some(commit.id.clone());
+            }
+        }
+
+        if self.version < 4 {
+            // Branches didn't exist, everything happened on the default branch
+            if let Some(last) = self.commits.last() {
+                if self.read_branch(DEFAULT_BRANCH).is_none() {
+                    self.write_branch(DEFAULT_BRANCH, &last.id)?;
+                }
+            }
+            if !self.repo_dir().join("HEAD").exists() {
+                self.set_head(&Head::Branch(DEFAULT_BRANCH.to_string()))?;
             }
         }
+
+        if self.version < 5 {
+            self.rewrite_commit_ids()?;
+        }
+
+        // Version 6 stopped recording working_dir; saving is enough to drop it
+
+        self.version = REPOSITORY_VERSION;
+        Ok(())
     }
 
-    // Helper function to rebuild objects store
-    fn rebuild_objects_store(&mut self) -> std::io::Result<()> {
-        self.objects.clear();
-        
-        // Rebuild from staged files
-        for (path, hash) in &self.staging {
-            let file_path = self.working_dir.join(path);
-            if file_path.exists() {
-                let content = fs::read(&file_path)?;
-                self.objects.insert(hash.clone(), content);
-            }
-        }
-
-        // Rebuild from committed files
-        for commit in &self.commits {
-            for (path, hash) in &commit.files {
-                if !self.objects.contains_key(hash) {
-                    let file_path = self.working_dir.join(path);
-                    if file_path.exists() {
-                        let content = fs::read(&file_path)?;
-                        self.objects.insert(hash.clone(), content);
+    // Replace random commit IDs with content hashes. Parents are rewritten
+    // before their children since a child's ID covers its parents' IDs.
+    fn rewrite_commit_ids(&mut self) -> std::io::Result<()> {
+        let mut renamed: HashMap<String, String> = HashMap::new();
+        let mut pending: Vec<usize> = (0..self.commits.len()).collect();
+
+        while !pending.is_empty() {
+            let ready: Vec<usize> = pending
+                .iter()
+                .copied()
+                .filter(|&i| {
+                    self.commits[i]
+                        .parents
+                        .iter()
+                        .all(|p| renamed.contains_key(p) || self.find_commit(p).is_none())
+                })
+                .collect();
+            if ready.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Commit history contains a cycle",
+                ));
+            }
+
+            for &i in &ready {
+                let commit = &mut self.commits[i];
+                for parent in &mut commit.parents {
+                    if let Some(new_id) = renamed.get(parent) {
+                        *parent = new_id.clone();
                     }
                 }
+                let new_id = commit.compute_id();
+                renamed.insert(std::mem::replace(&mut commit.id, new_id.clone()), new_id);
+            }
+            pending.retain(|i| !ready.contains(i));
+        }
+
+        for name in self.list_branches()? {
+            if let Some(new_id) = self.read_branch(&name).and_then(|id| renamed.get(&id)) {
+                self.write_branch(&name, new_id)?;
+            }
+        }
+        if let Head::Detached(id) = self.head() {
+            if let Some(new_id) = renamed.get(&id) {
+                self.set_head(&Head::Detached(new_id.clone()))?;
+            }
+        }
+        if let Some(new_id) = self.merge_head.as_ref().and_then(|id| renamed.get(id)) {
+            self.merge_head = Some(new_id.clone());
+        }
+
+        self.aliases.extend(renamed);
+        Ok(())
+    }
+
+    // Helper function to rebuild objects store
+    fn rebuild_objects_store(&self) -> std::io::Result<()> {
+        let referenced = self
+            .staging
+            .iter()
+            .chain(self.commits.iter().flat_map(|c| c.files.iter()));
+
+        for (path, hash) in referenced {
+            if !self.has_object(hash) {
+                self.recover_object(hash, path)?;
             }
         }
 
@@ -117,7 +527,7 @@ impl Repository {
         let content = fs::read(path)?;
         let hash = utils::calculate_hash_bytes(&content);
         
-        self.objects.insert(hash.clone(), content);
+        self.write_object(&hash, &content)?;
         
         let working_dir = self.working_dir.canonicalize()?;
         let canonical_path = path.canonicalize()?;
@@ -138,41 +548,897 @@ impl Repository {
             .to_string_lossy()
             .into_owned();
 
-        self.staging.insert(relative_path, hash);
+        self.stage_entry(&relative_path, &hash);
         Ok(())
     }
 
-    pub fn commit(&mut self, message: &str) -> std::io::Result<()> {
-        if self.staging.is_empty() {
+    // Record `hash` as the staged content of a path relative to the working dir.
+    // Staging only holds differences from HEAD, so content matching HEAD
+    // unstages the path instead.
+    pub fn stage_entry(&mut self, relative_path: &str, hash: &str) {
+        self.removed.remove(relative_path);
+        self.conflicts.remove(relative_path);
+        let in_head = self.head_commit().and_then(|c| c.files.get(relative_path)).is_some_and(|h| h == hash);
+        if in_head {
+            self.staging.remove(relative_path);
+        } else {
+            self.staging.insert(relative_path.to_string(), hash.to_string());
+        }
+    }
+
+    // Content the next commit would record for a path, if it keeps it at all
+    pub fn index_hash(&self, relative_path: &str) -> Option<String> {
+        self.staged_tree().remove(relative_path)
+    }
+
+    // Staged deletions paired with newly staged paths holding the same content,
+    // as (old path, new path)
+    pub fn staged_renames(&self) -> Vec<(String, String)> {
+        let head_files = self.head_files();
+        let mut renames = Vec::new();
+        let mut claimed = HashSet::new();
+
+        let mut removed: Vec<&String> = self.removed.iter().collect();
+        removed.sort();
+        for old_path in removed {
+            let Some(old_hash) = head_files.get(old_path) else {
+                continue;
+            };
+            let mut candidates: Vec<&String> = self
+                .staging
+                .iter()
+                .filter(|(path, hash)| *hash == old_hash && !head_files.contains_key(*path) && !claimed.contains(*path))
+                .map(|(path, _)| path)
+                .collect();
+            candidates.sort();
+            if let Some(new_path) = candidates.first() {
+                claimed.insert((*new_path).clone());
+                renames.push((old_path.clone(), (*new_path).clone()));
+            }
+        }
+        renames
+    }
+
+    // Stage the deletion of a tracked path, given relative to the working dir
+    // A conflicted path that HEAD doesn't have (deleted by us) is resolved by
+    // keeping it deleted, which leaves nothing to stage
+    pub fn stage_removal(&mut self, relative_path: &str) -> std::io::Result<()> {
+        let tracked = self.head_files().contains_key(relative_path);
+        let conflicted = self.conflicts.remove(relative_path).is_some();
+        if self.staging.remove(relative_path).is_none() && !tracked && !conflicted {
             return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Nothing to commit",
+                std::io::ErrorKind::NotFound,
+                format!("Path is not tracked: {}", relative_path),
             ));
         }
+        if tracked {
+            self.removed.insert(relative_path.to_string());
+        }
+        Ok(())
+    }
 
-        let commit = Commit {
-            id: utils::generate_commit_id(),
-            message: message.to_string(),
-            timestamp: Utc::now(),
-            files: self.staging.clone(),
+    pub fn head(&self) -> Head {
+        let content = fs::read_to_string(self.repo_dir().join("HEAD")).unwrap_or_default();
+        let content = content.trim();
+        match content.strip_prefix("ref: refs/heads/") {
+            Some(branch) => Head::Branch(branch.to_string()),
+            None if !content.is_empty() => Head::Detached(content.to_string()),
+            None => Head::Branch(DEFAULT_BRANCH.to_string()),
+        }
+    }
+
+    pub fn set_head(&self, head: &Head) -> std::io::Result<()> {
+        let content = match head {
+            Head::Branch(name) => format!("ref: refs/heads/{}\n", name),
+            Head::Detached(id) => format!("{}\n", id),
         };
+        fs::create_dir_all(self.repo_dir())?;
+        utils::write_file_atomic(&self.repo_dir().join("HEAD"), content.as_bytes())
+    }
+
+    pub fn current_branch(&self) -> Option<String> {
+        match self.head() {
+            Head::Branch(name) => Some(name),
+            Head::Detached(_) => None,
+        }
+    }
+
+    // Commit ID HEAD resolves to, None on an unborn branch
+    pub fn head_id(&self) -> Option<String> {
+        match self.head() {
+            Head::Branch(name) => self.read_branch(&name),
+            Head::Detached(id) => Some(id),
+        }
+    }
+
+    pub fn head_commit(&self) -> Option<&Commit> {
+        self.head_id().and_then(|id| self.find_commit(&id))
+    }
+
+    fn branch_path(&self, name: &str) -> PathBuf {
+        self.repo_dir().join("refs/heads").join(name)
+    }
+
+    // Branch names become paths under refs/heads, so keep them well formed
+    pub fn validate_branch_name(name: &str) -> std::io::Result<()> {
+        let valid = !name.is_empty()
+            && name != "HEAD"
+            && !name.starts_with('-')
+            && !name.starts_with('/')
+            && !name.ends_with('/')
+            && !name.contains("..")
+            && !name.contains("//")
+            && !name.chars().any(|c| c.is_whitespace() || c.is_control() || "~^:?*[\\".contains(c));
+        if valid {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid branch name: {}", name),
+            ))
+        }
+    }
+
+    pub fn read_branch(&self, name: &str) -> Option<String> {
+        read_ref(&self.branch_path(name))
+    }
+
+    pub fn write_branch(&self, name: &str, commit_id: &str) -> std::io::Result<()> {
+        write_ref(&self.branch_path(name), commit_id)
+    }
+
+    pub fn delete_branch(&self, name: &str) -> std::io::Result<()> {
+        fs::remove_file(self.branch_path(name))?;
+
+        // Drop directories left empty by names such as feature/foo
+        let heads_dir = self.repo_dir().join("refs/heads");
+        let mut dir = self.branch_path(name).parent().map(Path::to_path_buf);
+        while let Some(current) = dir {
+            if current == heads_dir || fs::remove_dir(&current).is_err() {
+                break;
+            }
+            dir = current.parent().map(Path::to_path_buf);
+        }
+        Ok(())
+    }
+
+    pub fn list_branches(&self) -> std::io::Result<Vec<String>> {
+        list_refs(&self.repo_dir().join("refs/heads"))
+    }
+
+    // Last known position of `branch` on `remote`, under refs/remotes/<remote>/<branch>
+    fn remote_ref_path(&self, remote: &str, branch: &str) -> PathBuf {
+        self.repo_dir().join("refs/remotes").join(remote).join(branch)
+    }
+
+    pub fn read_remote_ref(&self, remote: &str, branch: &str) -> Option<String> {
+        read_ref(&self.remote_ref_path(remote, branch))
+    }
+
+    pub fn write_remote_ref(&self, remote: &str, branch: &str, commit_id: &str) -> std::io::Result<()> {
+        write_ref(&self.remote_ref_path(remote, branch), commit_id)
+    }
+
+    // Branches we have tracking refs for under refs/remotes/<remote>
+    pub fn list_remote_refs(&self, remote: &str) -> std::io::Result<Vec<String>> {
+        list_refs(&self.repo_dir().join("refs/remotes").join(remote))
+    }
+
+    // Every ref as (what it is, the commit it names): branches,
+    // remote-tracking refs, a detached HEAD and a merge in progress. The
+    // commit is None for an empty or unreadable ref file.
+    pub fn refs(&self) -> std::io::Result<Vec<(String, Option<String>)>> {
+        let mut refs = Vec::new();
+        for branch in self.list_branches()? {
+            refs.push((format!("branch {}", branch), self.read_branch(&branch)));
+        }
+        let remotes_dir = self.repo_dir().join("refs/remotes");
+        for name in list_refs(&remotes_dir)? {
+            refs.push((format!("remote-tracking ref {}", name), read_ref(&remotes_dir.join(&name))));
+        }
+        if let Head::Detached(id) = self.head() {
+            refs.push(("HEAD".to_string(), Some(id)));
+        }
+        if let Some(merge_head) = &self.merge_head {
+            refs.push(("merge in progress".to_string(), Some(merge_head.clone())));
+        }
+        Ok(refs)
+    }
+
+    pub fn delete_remote_ref(&self, remote: &str, branch: &str) -> std::io::Result<()> {
+        fs::remove_file(self.remote_ref_path(remote, branch))
+    }
+
+    // Forget every tracking ref of a remote
+    pub fn delete_remote_refs(&self, remote: &str) -> std::io::Result<()> {
+        let remote_dir = self.repo_dir().join("refs/remotes").join(remote);
+        if remote_dir.exists() {
+            fs::remove_dir_all(remote_dir)?;
+        }
+        Ok(())
+    }
+
+    // Files recorded in the HEAD snapshot, empty before the first commit
+    pub fn head_files(&self) -> HashMap<String, String> {
+        self.head_commit().map(|c| c.files.clone()).unwrap_or_default()
+    }
+
+    // Full tree for the next commit: HEAD plus staged changes minus staged deletions
+    pub fn staged_tree(&self) -> HashMap<String, String> {
+        let mut files = self.head_files();
+        files.extend(self.staging.iter().map(|(p, h)| (p.clone(), h.clone())));
+        for path in &self.removed {
+            files.remove(path);
+        }
+        files
+    }
+
+    // Settings from every config layer, this repository's included
+    pub fn config(&self) -> std::io::Result<Config> {
+        Config::load(Some(&self.repo_dir()))
+    }
+
+    // Identity for the author or committer role: MINI_GIT_<ROLE>_NAME and
+    // MINI_GIT_<ROLE>_EMAIL from the environment, falling back to user.name
+    // and user.email from the config
+    pub fn identity(&self, role: &str, time: DateTime<FixedOffset>) -> std::io::Result<Signature> {
+        let config = self.config()?;
+        let lookup = |field: &str| {
+            std::env::var(format!("MINI_GIT_{}_{}", role.to_uppercase(), field.to_uppercase()))
+                .ok()
+                .filter(|value| !value.is_empty())
+                .or_else(|| config.get(&format!("user.{}", field)))
+        };
+
+        match (lookup("name"), lookup("email")) {
+            (Some(name), Some(email)) => Ok(Signature { name, email, time }),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "{} identity unknown; set user.name and user.email with \"mini-git config\" \
+                     or MINI_GIT_{}_NAME and MINI_GIT_{}_EMAIL",
+                    role,
+                    role.to_uppercase(),
+                    role.to_uppercase()
+                ),
+            )),
+        }
+    }
+
+    // Record the staged tree as a new commit. `author` overrides the configured identity.
+    pub fn commit(&mut self, message: &str, author: Option<&str>) -> std::io::Result<()> {
+        if !self.conflicts.is_empty() {
+            let mut paths: Vec<&str> = self.conflicts.keys().map(String::as_str).collect();
+            paths.sort();
+            return Err(std::io::Error::other(format!(
+                "Unresolved conflicts in: {}. Fix them and use \"mini-git add\" to mark them resolved",
+                paths.join(", ")
+            )));
+        }
+        // A merge is recorded even when its result matches HEAD
+        let files = self.staged_tree();
+        if self.merge_head.is_none() && files == self.head_files() {
+            return Err(std::io::Error::other("Nothing to commit"));
+        }
+
+        // A concluding merge commit also records the merged-in commit
+        let parents = self
+            .head_commit()
+            .map(|c| c.id.clone())
+            .into_iter()
+            .chain(self.merge_head.clone())
+            .collect();
+
+        let author = match author {
+            Some(text) => Some(Signature::parse(text, Local::now().fixed_offset())?),
+            None => None,
+        };
+        let commit_id = self.record_commit(parents, message, files, author)?;
 
-        self.commits.push(commit);
         self.staging.clear();
+        self.removed.clear();
+        self.merge_head = None;
         self.save()?;
-        Ok(())
+        self.advance_head(&commit_id)
+    }
+
+    // Add a commit of `files` to the history without touching HEAD or staging.
+    // The author defaults to the configured identity; the committer always is.
+    pub fn record_commit(
+        &mut self,
+        parents: Vec<String>,
+        message: &str,
+        files: HashMap<String, String>,
+        author: Option<Signature>,
+    ) -> std::io::Result<String> {
+        let now = Local::now().fixed_offset();
+        let author = match author {
+            Some(author) => author,
+            None => self.identity("Author", now)?,
+        };
+        let committer = self.identity("Committer", now)?;
+
+        let mut commit = Commit {
+            id: String::new(),
+            parents,
+            message: message.to_string(),
+            timestamp: now.with_timezone(&Utc),
+            files,
+            author: Some(author),
+            committer: Some(committer),
+        };
+        commit.id = commit.compute_id();
+
+        let commit_id = commit.id.clone();
+        self.commits.push(commit);
+        Ok(commit_id)
     }
 
-    pub fn get_commit(&self, commit_id: &str) -> Option<&Commit> {
-        self.commits.iter().find(|c| c.id.starts_with(commit_id))
+    // Move whatever HEAD points at, the current branch or a detached HEAD, to `commit_id`
+    pub fn advance_head(&self, commit_id: &str) -> std::io::Result<()> {
+        match self.head() {
+            Head::Branch(name) => self.write_branch(&name, commit_id),
+            Head::Detached(_) => self.set_head(&Head::Detached(commit_id.to_string())),
+        }
     }
 
-    pub fn get_object(&self, hash: &str) -> std::io::Result<Vec<u8>> {
-        self.objects.get(hash).cloned().ok_or_else(|| {
+    // Resolve HEAD, a branch name or a (possibly abbreviated) commit ID. An
+    // abbreviation has to name exactly one commit.
+    pub fn get_commit(&self, commit_id: &str) -> std::io::Result<&Commit> {
+        let not_found = || {
             std::io::Error::new(
                 std::io::ErrorKind::NotFound,
-                "Object not found in repository",
+                format!("Commit not found: {}", commit_id),
+            )
+        };
+        if commit_id == "HEAD" {
+            return self.head_commit().ok_or_else(not_found);
+        }
+        if let Some(id) = self.read_branch(commit_id) {
+            return self.find_commit(&id).ok_or_else(not_found);
+        }
+        if commit_id.is_empty() {
+            return Err(not_found());
+        }
+
+        // IDs from before the switch to content addressing still work
+        let matches: BTreeSet<&str> = self
+            .commits
+            .iter()
+            .map(|c| c.id.as_str())
+            .filter(|id| id.starts_with(commit_id))
+            .chain(
+                self.aliases
+                    .iter()
+                    .filter(|(old_id, _)| old_id.starts_with(commit_id))
+                    .map(|(_, new_id)| new_id.as_str()),
             )
+            .collect();
+        match matches.len() {
+            0 => Err(not_found()),
+            1 => self.find_commit(matches.first().unwrap()).ok_or_else(not_found),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Ambiguous commit ID {} could be any of: {}",
+                    commit_id,
+                    matches.iter().map(|id| &id[..8.min(id.len())]).collect::<Vec<_>>().join(", ")
+                ),
+            )),
+        }
+    }
+
+    // Exact lookup, used when following parent links
+    pub fn find_commit(&self, commit_id: &str) -> Option<&Commit> {
+        self.commits.iter().find(|c| c.id == commit_id)
+    }
+
+    // Commits by ID, for walks that would otherwise scan `commits` at every step
+    fn commit_map(&self) -> HashMap<&str, &Commit> {
+        self.commits.iter().map(|c| (c.id.as_str(), c)).collect()
+    }
+
+    // Every commit reachable from `commit_id`, including itself
+    pub fn ancestors(&self, commit_id: &str) -> HashSet<String> {
+        self.reachable_commits([commit_id])
+    }
+
+    // Every commit reachable from any of `tips`, including the tips themselves
+    pub fn reachable_commits<'a>(&self, tips: impl IntoIterator<Item = &'a str>) -> HashSet<String> {
+        let commits = self.commit_map();
+        let mut seen = HashSet::new();
+        let mut pending: Vec<String> = tips.into_iter().map(String::from).collect();
+        while let Some(id) = pending.pop() {
+            if !seen.insert(id.clone()) {
+                continue;
+            }
+            if let Some(commit) = commits.get(id.as_str()) {
+                pending.extend(commit.parents.iter().cloned());
+            }
+        }
+        seen
+    }
+
+    pub fn is_ancestor(&self, ancestor_id: &str, descendant_id: &str) -> bool {
+        self.ancestors(descendant_id).contains(ancestor_id)
+    }
+
+    // Best common ancestor of two commits: a common ancestor that is not itself
+    // an ancestor of another common ancestor, preferring the most recent one.
+    //
+    // Walks back from both tips at once, newest first, marking each commit
+    // with the sides it is reachable from. A commit reached from both sides
+    // is a candidate, and everything behind it is marked stale so the walk
+    // stops once only stale commits are left.
+    pub fn merge_base(&self, commit_id1: &str, commit_id2: &str) -> Option<String> {
+        const FROM_1: u8 = 1;
+        const FROM_2: u8 = 2;
+        const STALE: u8 = 4;
+
+        let commits = self.commit_map();
+        let tip1 = *commits.get(commit_id1)?;
+        let tip2 = *commits.get(commit_id2)?;
+        if tip1.id == tip2.id {
+            return Some(tip1.id.clone());
+        }
+
+        let mut flags: HashMap<&str, u8> = HashMap::from([(tip1.id.as_str(), FROM_1), (tip2.id.as_str(), FROM_2)]);
+        let mut queue = BinaryHeap::from([(tip1.timestamp, tip1.id.as_str()), (tip2.timestamp, tip2.id.as_str())]);
+        let mut candidates: Vec<&Commit> = Vec::new();
+
+        while queue.iter().any(|(_, id)| flags[id] & STALE == 0) {
+            let Some((_, id)) = queue.pop() else { break };
+            let mut flag = flags[id];
+            if flag & (FROM_1 | FROM_2) == FROM_1 | FROM_2 && flag & STALE == 0 {
+                candidates.push(commits[id]);
+                flag |= STALE;
+                flags.insert(id, flag);
+            }
+            for parent in commits[id].parents.iter().filter_map(|p| commits.get(p.as_str())) {
+                let old = flags.get(parent.id.as_str()).copied().unwrap_or(0);
+                let new = old | flag;
+                if new != old {
+                    flags.insert(parent.id.as_str(), new);
+                    queue.push((parent.timestamp, parent.id.as_str()));
+                }
+            }
+        }
+
+        // Skewed timestamps can let an older candidate be found first
+        if candidates.len() > 1 {
+            let all = candidates.clone();
+            candidates.retain(|candidate| {
+                !all.iter().any(|other| other.id != candidate.id && self.is_ancestor(&candidate.id, &other.id))
+            });
+        }
+        candidates.into_iter().max_by_key(|c| c.timestamp).map(|c| c.id.clone())
+    }
+
+    // Commits reachable from `commit_id`, newest first
+    pub fn walk_history(&self, commit_id: &str) -> Vec<&Commit> {
+        let commits = self.commit_map();
+        let mut history = Vec::new();
+        let mut seen = HashSet::new();
+        let mut queue = BinaryHeap::new();
+
+        if let Some(start) = commits.get(commit_id) {
+            queue.push((start.timestamp, start.id.as_str()));
+        }
+        while let Some((_, id)) = queue.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            let Some(commit) = commits.get(id).copied() else {
+                continue;
+            };
+            for parent in commit.parents.iter().filter_map(|p| commits.get(p.as_str())) {
+                queue.push((parent.timestamp, parent.id.as_str()));
+            }
+            history.push(commit);
+        }
+        history
+    }
+
+    // Copy the commits reachable from `tip` that this repository lacks, along
+    // with the objects their trees use. Returns how many commits were added.
+    pub fn import_commits(&mut self, source: &Repository, tip: &str) -> std::io::Result<usize> {
+        let known: HashSet<&str> = self.commits.iter().map(|c| c.id.as_str()).collect();
+        let reachable = source.ancestors(tip);
+        // Source order puts parents before their children
+        let missing: Vec<Commit> = source
+            .commits
+            .iter()
+            .filter(|c| reachable.contains(&c.id) && !known.contains(c.id.as_str()))
+            .cloned()
+            .collect();
+
+        // Objects go first so a recorded commit never refers to missing content
+        for commit in &missing {
+            for hash in commit.files.values() {
+                if !self.has_object(hash) {
+                    self.write_object(hash, &source.get_object(hash)?)?;
+                }
+            }
+        }
+
+        let count = missing.len();
+        self.commits.extend(missing);
+        Ok(count)
+    }
+
+    pub fn objects_dir(&self) -> PathBuf {
+        self.repo_dir().join("objects")
+    }
+
+    // Loose objects live at objects/<first two hex chars>/<remaining hex chars>
+    pub fn object_path(&self, hash: &str) -> std::io::Result<PathBuf> {
+        if hash.len() < 3 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid object hash: {}", hash),
+            ));
+        }
+        Ok(self.objects_dir().join(&hash[..2]).join(&hash[2..]))
+    }
+
+    // Hashes of every object in the store, sorted
+    pub fn list_objects(&self) -> std::io::Result<Vec<String>> {
+        let objects_dir = self.objects_dir();
+        let mut hashes = Vec::new();
+        if !objects_dir.exists() {
+            return Ok(hashes);
+        }
+        for entry in fs::read_dir(&objects_dir)? {
+            let entry = entry?;
+            let prefix = entry.file_name().to_string_lossy().into_owned();
+            // Temporary files of writes in progress sit directly in objects/
+            if prefix.len() != 2 || !entry.file_type()?.is_dir() {
+                continue;
+            }
+            for object in fs::read_dir(entry.path())? {
+                let hash = format!("{}{}", prefix, object?.file_name().to_string_lossy());
+                if hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    hashes.push(hash);
+                }
+            }
+        }
+        hashes.sort();
+        Ok(hashes)
+    }
+
+    // Restore an object from the working tree copy of `path`, but only if that
+    // still has exactly the content hashed. Replaces a corrupt stored copy.
+    // Returns whether the object was written.
+    pub fn recover_object(&self, hash: &str, path: &str) -> std::io::Result<bool> {
+        let file_path = self.working_dir.join(path);
+        if !file_path.is_file() {
+            return Ok(false);
+        }
+        let content = fs::read(&file_path)?;
+        if utils::calculate_hash_bytes(&content) != hash {
+            return Ok(false);
+        }
+        let (temp_path, mut temp) = self.create_object_temp()?;
+        if let Err(e) = temp.write_all(&content) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+        install_object(&temp_path, &self.object_path(hash)?)?;
+        Ok(true)
+    }
+
+    pub fn has_object(&self, hash: &str) -> bool {
+        self.object_path(hash).map(|p| p.is_file()).unwrap_or(false)
+    }
+
+    // Objects are immutable, so an existing file is never rewritten
+    pub fn write_object(&self, hash: &str, content: &[u8]) -> std::io::Result<()> {
+        let object_path = self.object_path(hash)?;
+        if object_path.exists() {
+            return Ok(());
+        }
+        let (temp_path, mut temp) = self.create_object_temp()?;
+        if let Err(e) = temp.write_all(content) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+        install_object(&temp_path, &object_path)
+    }
+
+    // Copy a file into the object store, hashing it on the way so it never has
+    // to be held in memory; returns the hash of what was copied
+    pub fn store_file(&self, path: &Path) -> std::io::Result<String> {
+        let mut file = fs::File::open(path)?;
+        let (temp_path, mut temp) = self.create_object_temp()?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; utils::CHUNK_SIZE];
+        let copied = loop {
+            match file.read(&mut buffer) {
+                Ok(0) => break Ok(()),
+                Ok(read) => {
+                    hasher.update(&buffer[..read]);
+                    if let Err(e) = temp.write_all(&buffer[..read]) {
+                        break Err(e);
+                    }
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        if let Err(e) = copied {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+
+        let hash = format!("{:x}", hasher.finalize());
+        let object_path = self.object_path(&hash)?;
+        if object_path.exists() {
+            fs::remove_file(&temp_path)?;
+        } else {
+            install_object(&temp_path, &object_path)?;
+        }
+        Ok(hash)
+    }
+
+    // Objects are written under a unique temporary name and renamed into
+    // place, so concurrent or interrupted writes never leave a partial object
+    fn create_object_temp(&self) -> std::io::Result<(PathBuf, fs::File)> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let objects_dir = self.objects_dir();
+        fs::create_dir_all(&objects_dir)?;
+        let temp_path = objects_dir.join(format!(
+            "tmp-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temp = fs::File::create_new(&temp_path)?;
+        Ok((temp_path, temp))
+    }
+
+    pub fn get_object(&self, hash: &str) -> std::io::Result<Vec<u8>> {
+        let object_path = self.object_path(hash)?;
+        fs::read(object_path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "Object not found in repository",
+            ),
+            _ => e,
         })
     }
 }
+
+// A ref file holds a single commit ID. It's replaced atomically, since a
+// truncated ref would make its branch look unborn.
+fn read_ref(path: &Path) -> Option<String> {
+    let content = fs::read_to_string(path).ok()?;
+    let id = content.trim();
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+fn write_ref(path: &Path, commit_id: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    utils::write_file_atomic(path, format!("{}\n", commit_id).as_bytes())
+}
+
+// Names of the refs under `dir`, nested ones as "a/b", sorted
+fn list_refs(dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    if dir.exists() {
+        for entry in WalkDir::new(dir)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|e| e.file_type().is_file())
+        {
+            if let Ok(name) = entry.path().strip_prefix(dir) {
+                names.push(name.to_string_lossy().into_owned());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn install_object(temp_path: &Path, object_path: &Path) -> std::io::Result<()> {
+    let result = match object_path.parent() {
+        Some(parent) => fs::create_dir_all(parent).and_then(|_| fs::rename(temp_path, object_path)),
+        None => fs::rename(temp_path, object_path),
+    };
+    if result.is_err() {
+        let _ = fs::remove_file(temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDir;
+    use chrono::TimeZone;
+
+    // Record a commit with a readable ID; `secs` orders the merge_base walk
+    fn add_commit(repo: &mut Repository, id: &str, parents: &[&str], secs: i64) {
+        repo.commits.push(Commit {
+            timestamp: Utc.timestamp_opt(secs, 0).unwrap(),
+            id: id.to_string(),
+            parents: parents.iter().map(|p| p.to_string()).collect(),
+            message: id.to_string(),
+            files: HashMap::new(),
+            author: None,
+            committer: None,
+        });
+    }
+
+    fn history(commits: &[(&str, &[&str], i64)]) -> Repository {
+        let mut repo = Repository::new(PathBuf::from("/nonexistent"));
+        for (id, parents, secs) in commits {
+            add_commit(&mut repo, id, parents, *secs);
+        }
+        repo
+    }
+
+    #[test]
+    fn merge_base_of_ancestors_is_the_older_commit() {
+        let repo = history(&[("a", &[], 1), ("b", &["a"], 2), ("c", &["b"], 3)]);
+        assert_eq!(repo.merge_base("c", "a").as_deref(), Some("a"));
+        assert_eq!(repo.merge_base("a", "c").as_deref(), Some("a"));
+        assert_eq!(repo.merge_base("b", "c").as_deref(), Some("b"));
+        assert_eq!(repo.merge_base("c", "c").as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn merge_base_of_diverged_branches() {
+        let repo = history(&[
+            ("root", &[], 1),
+            ("fork", &["root"], 2),
+            ("left1", &["fork"], 3),
+            ("left2", &["left1"], 5),
+            ("right", &["fork"], 4),
+            // An earlier merge of right into left moves the base up to right
+            ("left3", &["left2", "right"], 6),
+            ("right2", &["right"], 7),
+        ]);
+        assert_eq!(repo.merge_base("left2", "right").as_deref(), Some("fork"));
+        assert_eq!(repo.merge_base("left3", "right2").as_deref(), Some("right"));
+    }
+
+    #[test]
+    fn merge_base_of_criss_cross_merges() {
+        // a2 and b2 each merged the other side's first commit, so a1 and b1
+        // are both best common ancestors; the newer one wins
+        let repo = history(&[
+            ("root", &[], 1),
+            ("a1", &["root"], 2),
+            ("b1", &["root"], 3),
+            ("a2", &["a1", "b1"], 4),
+            ("b2", &["b1", "a1"], 5),
+        ]);
+        assert_eq!(repo.merge_base("a2", "b2").as_deref(), Some("b1"));
+        assert_eq!(repo.merge_base("b2", "a2").as_deref(), Some("b1"));
+    }
+
+    #[test]
+    fn merge_base_of_unrelated_histories() {
+        let repo = history(&[("a", &[], 1), ("a2", &["a"], 2), ("b", &[], 3), ("b2", &["b"], 4)]);
+        assert_eq!(repo.merge_base("a2", "b2"), None);
+        assert_eq!(repo.merge_base("a2", "missing"), None);
+    }
+
+    #[test]
+    fn merge_base_ignores_skewed_timestamps() {
+        // "base" claims to be older than its own parent, so the walk reaches
+        // "root" first; it must still not be chosen over its descendant
+        let repo = history(&[
+            ("root", &[], 10),
+            ("base", &["root"], 1),
+            ("ours", &["base"], 20),
+            ("theirs", &["base", "root"], 21),
+        ]);
+        assert_eq!(repo.merge_base("ours", "theirs").as_deref(), Some("base"));
+    }
+
+    fn sample_commit() -> Commit {
+        let time = FixedOffset::east_opt(2 * 3600).unwrap().with_ymd_and_hms(2024, 5, 6, 7, 8, 9).unwrap();
+        let signature = Signature { name: "Ada".to_string(), email: "ada@example.com".to_string(), time };
+        Commit {
+            timestamp: time.with_timezone(&Utc),
+            id: String::new(),
+            parents: vec!["p1".to_string(), "p2".to_string()],
+            message: "Subject\n\nBody\n".to_string(),
+            files: HashMap::from([
+                ("b.txt".to_string(), "22".to_string()),
+                ("a.txt".to_string(), "11".to_string()),
+            ]),
+            author: Some(signature.clone()),
+            committer: Some(signature),
+        }
+    }
+
+    #[test]
+    fn commit_ids_are_stable() {
+        let commit = sample_commit();
+        assert_eq!(
+            commit.canonical_form(),
+            "file 11 a.txt\nfile 22 b.txt\nparent p1\nparent p2\n\
+             author Ada <ada@example.com> 1714972089 +0200\n\
+             committer Ada <ada@example.com> 1714972089 +0200\n\
+             timestamp 2024-05-06T05:08:09.000000000Z\n\nSubject\n\nBody\n"
+        );
+        assert_eq!(commit.compute_id(), utils::calculate_hash_bytes(commit.canonical_form().as_bytes()));
+
+        // Map order doesn't matter, everything else does
+        let mut reordered = commit.clone();
+        reordered.files = HashMap::from([
+            ("a.txt".to_string(), "11".to_string()),
+            ("b.txt".to_string(), "22".to_string()),
+        ]);
+        assert_eq!(reordered.compute_id(), commit.compute_id());
+
+        let mut changes: Vec<Commit> = vec![commit.clone(); 5];
+        changes[0].message.push('!');
+        changes[1].parents.reverse();
+        changes[2].files.insert("a.txt".to_string(), "12".to_string());
+        changes[3].author.as_mut().unwrap().name = "Bob".to_string();
+        changes[4].timestamp += chrono::Duration::nanoseconds(1);
+        for changed in changes {
+            assert_ne!(changed.compute_id(), commit.compute_id(), "{:?}", changed);
+        }
+    }
+
+    #[test]
+    fn upgrade_rewrites_random_ids() {
+        let dir = TempDir::new("upgrade-ids");
+        let first = "0b6f3c9e-1d2a-4c5b-8e7f-000000000001";
+        let second = "0b6f3c9e-1d2a-4c5b-8e7f-000000000002";
+        let side = "7a7a7a7a-1d2a-4c5b-8e7f-000000000003";
+        {
+            let mut repo = history(&[(first, &[], 1), (second, &[first], 2), (side, &[first], 3)]);
+            repo.version = 4;
+            repo.working_dir = dir.0.clone();
+            repo.git_dir = dir.0.join(".mini-git");
+            repo.save().unwrap();
+            repo.write_branch(DEFAULT_BRANCH, second).unwrap();
+            repo.write_branch("side", side).unwrap();
+            repo.set_head(&Head::Detached(side.to_string())).unwrap();
+        }
+
+        let repo = Repository::load(dir.0.clone()).unwrap();
+        assert_eq!(repo.version, REPOSITORY_VERSION);
+        for commit in &repo.commits {
+            assert_eq!(commit.id, commit.compute_id());
+        }
+        let new_first = &repo.aliases[first];
+        let new_second = &repo.aliases[second];
+        let new_side = &repo.aliases[side];
+        assert_eq!(&repo.find_commit(new_second).unwrap().parents, std::slice::from_ref(new_first));
+        assert_eq!(repo.read_branch(DEFAULT_BRANCH).as_ref(), Some(new_second));
+        assert_eq!(repo.read_branch("side").as_ref(), Some(new_side));
+        assert_eq!(repo.head(), Head::Detached(new_side.clone()));
+
+        // Old IDs, whole or abbreviated, still resolve
+        assert_eq!(&repo.get_commit(second).unwrap().id, new_second);
+        assert_eq!(&repo.get_commit("7a7a").unwrap().id, new_side);
+        assert_eq!(repo.get_commit("0b6f3c9e").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn get_commit_rejects_ambiguous_prefixes() {
+        let mut repo = history(&[("abc1", &[], 1), ("abc2", &[], 2), ("def", &[], 3)]);
+        repo.aliases.insert("old-1".to_string(), "abc1".to_string());
+        repo.aliases.insert("old-2".to_string(), "def".to_string());
+        repo.aliases.insert("abd".to_string(), "def".to_string());
+
+        assert_eq!(repo.get_commit("abc1").unwrap().id, "abc1");
+        assert_eq!(repo.get_commit("d").unwrap().id, "def");
+        assert_eq!(repo.get_commit("old-1").unwrap().id, "abc1");
+        // An ID and an alias of the same commit don't make it ambiguous
+        assert_eq!(repo.get_commit("abd").unwrap().id, "def");
+        for ambiguous in ["abc", "ab", "old"] {
+            let error = repo.get_commit(ambiguous).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput, "{}", ambiguous);
+        }
+        for missing in ["", "x", "abc3"] {
+            assert_eq!(repo.get_commit(missing).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        }
+    }
+}
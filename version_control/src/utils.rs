@@ -1,8 +1,10 @@
 use sha2::{Sha256, Digest};
-use uuid::Uuid;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{Read, Write};
 use std::path::Path;
 
+// Files are hashed and copied in chunks of this size rather than read whole
+pub const CHUNK_SIZE: usize = 64 * 1024;
 
 pub fn calculate_hash_bytes(content: &[u8]) -> String {
     let mut hasher = Sha256::new();
@@ -10,32 +12,68 @@ pub fn calculate_hash_bytes(content: &[u8]) -> String {
     format!("{:x}", hasher.finalize())
 }
 
-pub fn generate_commit_id() -> String {
-    Uuid::new_v4().to_string()
+// Same as calculate_hash_bytes on the file's content, without loading it all
+pub fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
 }
 
-pub fn copy_dir_contents(src: &Path, dst: &Path) -> std::io::Result<()> {
-    if !dst.exists() {
-        fs::create_dir_all(dst)?;
-    }
+// Replace `path` so that a crash leaves either the old or the new content:
+// write a temporary file next to it, flush it to disk, then rename it over
+pub fn write_file_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
 
-    for entry in fs::read_dir(src)? {
-        let entry = entry?;
-        let ty = entry.file_type()?;
-        let src_path = entry.path();
-        
-        // Skip the .mini-git directory
-        if src_path.ends_with(".mini-git") {
-            continue;
-        }
-        
-        let dst_path = dst.join(entry.file_name());
+    let written = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(content)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&temp_path, path)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
 
-        if ty.is_dir() {
-            copy_dir_contents(&src_path, &dst_path)?;
-        } else {
-            fs::copy(&src_path, &dst_path)?;
-        }
+    // Make the rename itself durable
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
     }
     Ok(())
 }
+
+// Same heuristic as git: content with a NUL byte is treated as binary
+pub fn is_binary(content: &[u8]) -> bool {
+    content.contains(&0)
+}
+
+// Scratch directory for tests that need a repository on disk, removed again
+// when dropped
+#[cfg(test)]
+pub struct TempDir(pub std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("mini-git-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}